@@ -5,47 +5,121 @@ extern crate rand;
 
 use rand::Rng;
 
-use lazy_transform::LazyTransform;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lazy_transform::{LazyTransform, TransformerThread};
+
+// Counts allocations made by all threads, so that those made by transforms
+// running in readers are included.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+fn allocations() -> u64 {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
 
 #[derive(Debug, Clone)]
 struct Payload(String);
 
-fn parse_bytes(b: Box<[u8]>) -> Option<Payload> {
+fn parse_bytes(b: &[u8]) -> Option<Payload> {
     let start = time::precise_time_ns();
     let mut d = time::precise_time_ns() as f64 / start as f64;
     for _ in 0..10_000 {
         d *= 1.00001;
     }
 
-    Some(Payload(String::from_utf8(b.into_vec()).unwrap()
+    Some(Payload(String::from_utf8(b.to_vec()).unwrap()
                  + &format!("{}", d)))
 }
 
+// Transform function that lets the buffer go.
+fn parse_boxed(b: Box<[u8]>) -> Option<Payload> {
+    parse_bytes(&b)
+}
+
+// Like parse_boxed, but leave the buffer to POOL.
+fn parse_bytes_recycling(pool: Arc<BufferPool>)
+                         -> impl Fn(Box<[u8]>) -> Option<Payload> {
+    move |b: Box<[u8]>| {
+        let payload = parse_bytes(&b);
+        pool.put(b);
+        payload
+    }
+}
+
+const SOURCE_LEN: usize = 3;
+
+fn new_buffer() -> Box<[u8]> {
+    vec![0; SOURCE_LEN].into_boxed_slice()
+}
+
+// Buffers handed back by the transform function and by
+// LazyTransform::on_source_dropped, for the producer to reuse.
+struct BufferPool(Mutex<Vec<Box<[u8]>>>);
+
+impl BufferPool {
+    fn take(&self) -> Box<[u8]> {
+        self.0.lock().unwrap().pop().unwrap_or_else(new_buffer)
+    }
+
+    fn put(&self, buffer: Box<[u8]>) {
+        self.0.lock().unwrap().push(buffer);
+    }
+}
+
 type BenchLazyTransform<FN> = LazyTransform<Payload, Box<[u8]>, FN>;
 
 const PRODUCE_ITERS: usize = 1_000_000;
 const CONSUME_ITERS: usize = 100_000_000;
 
-fn produce<FN>(lt: &BenchLazyTransform<FN>)
-    where FN: Fn(Box<[u8]>) -> Option<Payload>
+// Returns the number of allocations per set_source, made by any thread
+// while the producer runs.
+fn produce<FN, B>(lt: &BenchLazyTransform<FN>, buffer: &B) -> f64
+    where FN: Fn(Box<[u8]>) -> Option<Payload>,
+          B: Fn() -> Box<[u8]>
 {
     fn random_byte() -> u8 {
-        'A' as u8 + rand::thread_rng().gen_range(0u8, 10)
+        b'A' + rand::thread_rng().gen_range(0u8, 10)
     }
 
     let start = time::precise_time_ns();
+    let allocations_start = allocations();
     for _i in 0..PRODUCE_ITERS {
-        lt.set_source((0..3).map(|_| random_byte())
-                      .collect::<Vec<_>>().into_boxed_slice());
+        let mut source = buffer();
+        for byte in source.iter_mut() {
+            *byte = random_byte();
+        }
+        lt.set_source(source);
         simulate_work();
     }
+    let allocations = allocations() - allocations_start;
     let elapsed = time::precise_time_ns() - start;
-    println!("Producer took {} ns/op ({} s)",
+    println!("Producer took {} ns/op ({} s, {} allocations/op)",
              elapsed as f64 / PRODUCE_ITERS as f64,
-             elapsed as f64 / 1e9);
+             elapsed as f64 / 1e9,
+             allocations as f64 / PRODUCE_ITERS as f64);
+    allocations as f64 / PRODUCE_ITERS as f64
 }
 
-fn consume<FN>(lt: &BenchLazyTransform<FN>)
+// Returns the time per read in ns.
+fn consume<FN>(lt: &BenchLazyTransform<FN>) -> f64
     where FN: Fn(Box<[u8]>) -> Option<Payload>
 {
     let start = time::precise_time_ns();
@@ -57,10 +131,119 @@ fn consume<FN>(lt: &BenchLazyTransform<FN>)
             }
         }
     }
+    report_consume(start, count)
+}
+
+fn consume_with_reader<FN>(lt: &BenchLazyTransform<FN>) -> f64
+    where FN: Fn(Box<[u8]>) -> Option<Payload>
+{
+    let start = time::precise_time_ns();
+    let mut count = 0u64;
+    let mut reader = lt.reader();
+    for _ in 0..CONSUME_ITERS {
+        if let Some(o) = reader.get() {
+            if o.0 == "longer" {
+                count += 1;
+            }
+        }
+    }
+    report_consume(start, count)
+}
+
+fn report_consume(start: u64, count: u64) -> f64 {
     let elapsed = time::precise_time_ns() - start;
+    let ns_per_op = elapsed as f64 / CONSUME_ITERS as f64;
     println!("Consumer took {} ns/op ({} s, count {})",
-             elapsed as f64 / CONSUME_ITERS as f64,
-             elapsed as f64 / 1e9, count);
+             ns_per_op, elapsed as f64 / 1e9, count);
+    ns_per_op
+}
+
+// Histogram of read latencies, in buckets of LATENCY_BUCKET_NS.
+struct Latencies {
+    counts: Vec<u64>,
+    max: u64,
+}
+
+const LATENCY_BUCKET_NS: u64 = 10;
+const LATENCY_BUCKETS: usize = 100_000;
+
+impl Latencies {
+    fn new() -> Latencies {
+        Latencies { counts: vec![0; LATENCY_BUCKETS], max: 0 }
+    }
+
+    fn record(&mut self, ns: u64) {
+        let bucket = (ns / LATENCY_BUCKET_NS) as usize;
+        self.counts[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+        self.max = self.max.max(ns);
+    }
+
+    fn merge(&mut self, other: &Latencies) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.max = self.max.max(other.max);
+    }
+
+    // Upper bound of the latency under which fraction Q of the reads fall.
+    fn quantile(&self, q: f64) -> u64 {
+        let total: u64 = self.counts.iter().sum();
+        let wanted = (total as f64 * q).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                return (bucket as u64 + 1) * LATENCY_BUCKET_NS;
+            }
+        }
+        self.max
+    }
+
+    fn report(&self, name: &str) {
+        println!("{}: p50 {} ns, p99 {} ns, p99.9 {} ns, p99.99 {} ns, \
+                  max {} ns", name, self.quantile(0.5), self.quantile(0.99),
+                 self.quantile(0.999), self.quantile(0.9999), self.max);
+    }
+}
+
+// Time each read separately, as long as the producer is running.
+fn consume_timed<FN>(lt: &BenchLazyTransform<FN>, done: &AtomicBool)
+                     -> Latencies
+    where FN: Fn(Box<[u8]>) -> Option<Payload>
+{
+    let mut latencies = Latencies::new();
+    let mut count = 0u64;
+    while !done.load(Ordering::Relaxed) {
+        let start = time::precise_time_ns();
+        if let Some(o) = lt.get_transformed() {
+            if o.0 == "longer" {
+                count += 1;
+            }
+        }
+        latencies.record(time::precise_time_ns() - start);
+    }
+    assert_eq!(count, 0);
+    latencies
+}
+
+// Run the producer once against timed consumers and return their combined
+// read latencies.
+fn run_timed<FN>(lt: &BenchLazyTransform<FN>) -> Latencies
+    where FN: Fn(Box<[u8]>) -> Option<Payload> + Send
+{
+    let done = AtomicBool::new(false);
+    let mut latencies = Latencies::new();
+    crossbeam::scope(|scope| {
+        let consumers: Vec<_> = (0..8)
+            .map(|_| scope.spawn(|| consume_timed(lt, &done)))
+            .collect();
+        produce(lt, &new_buffer);
+        done.store(true, Ordering::Relaxed);
+        for consumer in consumers {
+            latencies.merge(&consumer.join());
+        }
+    });
+    latencies
 }
 
 fn simulate_work() {
@@ -76,15 +259,55 @@ fn simulate_work() {
     }
 }
 
-fn main() {
-    let lt = LazyTransform::new(parse_bytes);
+// Run producer and consumers three times, returning the average time per
+// read in ns and the average number of allocations per set_source.  Those
+// include what the consumers allocate, which for consume is a clone per
+// read.
+fn run<FN, C, B>(lt: &BenchLazyTransform<FN>, consume: C, buffer: B)
+                 -> (f64, f64)
+    where FN: Fn(Box<[u8]>) -> Option<Payload> + Send,
+          C: Fn(&BenchLazyTransform<FN>) -> f64 + Sync,
+          B: Fn() -> Box<[u8]>
+{
+    let (mut read_total, mut alloc_total) = (0f64, 0f64);
     for _ in 0..3 {
         crossbeam::scope(|scope| {
-            for _ in 0..8 {
-                scope.spawn(|| consume(&lt));
-            }
+            let consumers: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| consume(lt)))
+                .collect();
             println!("Start producing");
-            produce(&lt);
+            alloc_total += produce(lt, &buffer);
+            read_total += consumers.into_iter().map(|c| c.join()).sum::<f64>();
         });
     }
+    (read_total / 24., alloc_total / 3.)
+}
+
+fn main() {
+    let lt = LazyTransform::new(parse_boxed);
+    println!("Reading with get_transformed");
+    let (plain, _) = run(&lt, consume, new_buffer);
+    println!("Reading with a reader handle");
+    let (cached, allocating) = run(&lt, consume_with_reader, new_buffer);
+    println!("get_transformed {:.2} ns/op, reader {:.2} ns/op, speedup {:.1}x",
+             plain, cached, plain / cached);
+
+    let pool = Arc::new(BufferPool(Mutex::new(Vec::new())));
+    let lt = LazyTransform::new(parse_bytes_recycling(Arc::clone(&pool)))
+        .on_source_dropped({
+            let pool = Arc::clone(&pool);
+            move |b| pool.put(b)
+        });
+    println!("Recycling source buffers");
+    let (_, recycling) = run(&lt, consume_with_reader, || pool.take());
+    println!("set_source {:.2} allocations/op, with recycling {:.2} \
+              allocations/op", allocating, recycling);
+
+    println!("Timing reads of a lazy instance");
+    let lazy = run_timed(&LazyTransform::new(parse_boxed));
+    println!("Timing reads of an eager instance");
+    let eager = run_timed(&LazyTransform::new(parse_boxed)
+                          .into_eager(TransformerThread::new()));
+    lazy.report("lazy get_transformed");
+    eager.report("eager get_transformed");
 }
@@ -1,3 +1,6 @@
+// Article snapshot: kept as published, so newer lints are silenced.
+#![allow(clippy::redundant_field_names)]
+
 use std::sync::Mutex;
 
 pub struct LazyTransform<T, S, FN> {
@@ -1,3 +1,6 @@
+// Article snapshot: kept as published, so newer lints are silenced.
+#![allow(clippy::redundant_field_names, clippy::redundant_pattern_matching)]
+
 use std::sync::{Mutex, RwLock};
 
 pub struct LazyTransform<T, S, FN> {
@@ -1,3 +1,6 @@
+// Article snapshot: kept as published, so newer lints are silenced.
+#![allow(clippy::map_identity, clippy::redundant_field_names)]
+
 use std::sync::{Mutex, RwLock};
 use std::sync::atomic::{AtomicBool, Ordering};
 
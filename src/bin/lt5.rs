@@ -1,3 +1,11 @@
+// Article snapshot: kept as published, so newer lints are silenced.
+#![allow(
+    clippy::map_identity,
+    clippy::needless_borrow,
+    clippy::question_mark,
+    clippy::redundant_field_names
+)]
+
 extern crate crossbeam;
 
 use std::sync::atomic::{AtomicBool, Ordering};
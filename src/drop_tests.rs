@@ -0,0 +1,269 @@
+use lazy_transform::{Fallible, LazyTransform, RetryPolicy, TransformerThread};
+
+use crossbeam_epoch as epoch;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Record of tracked objects, indexed by id: how many times each has been
+// dropped.
+struct DropLog(Mutex<Vec<u32>>);
+
+impl DropLog {
+    fn new() -> Arc<DropLog> {
+        Arc::new(DropLog(Mutex::new(Vec::new())))
+    }
+
+    fn created(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    fn is_settled(&self) -> bool {
+        self.0.lock().unwrap().iter().all(|&drops| drops != 0)
+    }
+
+    // Check that every object created so far has been dropped exactly once.
+    // Replaced sources and values are destroyed only after the epoch has
+    // advanced, so keep nudging it for a while before giving up.
+    fn assert_dropped_once(&self) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !self.is_settled() && Instant::now() < deadline {
+            epoch::pin().flush();
+            thread::sleep(Duration::from_millis(1));
+        }
+        for (id, &drops) in self.0.lock().unwrap().iter().enumerate() {
+            assert_eq!(drops, 1, "object {} dropped {} times", id, drops);
+        }
+    }
+}
+
+// Used both as source and as value.  Clones are distinct objects.
+struct Tracked {
+    id: usize,
+    payload: u64,
+    log: Arc<DropLog>,
+}
+
+impl Tracked {
+    fn new(payload: u64, log: &Arc<DropLog>) -> Tracked {
+        let mut drops = log.0.lock().unwrap();
+        drops.push(0);
+        Tracked { id: drops.len() - 1, payload, log: Arc::clone(log) }
+    }
+}
+
+impl Clone for Tracked {
+    fn clone(&self) -> Tracked {
+        Tracked::new(self.payload, &self.log)
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.log.0.lock().unwrap()[self.id] += 1;
+    }
+}
+
+// Transform that fails for payloads divisible by 3, reporting the failure
+// with a tracked error.
+fn tracked_transform(log: &Arc<DropLog>)
+                     -> impl Fn(Tracked) -> Result<Tracked, Tracked> {
+    let log = Arc::clone(log);
+    move |source: Tracked| {
+        let result = Tracked::new(source.payload * 10, &log);
+        if source.payload.is_multiple_of(3) { Err(result) } else { Ok(result) }
+    }
+}
+
+#[test]
+fn drop_overwritten_sources() {
+    let log = DropLog::new();
+    let lt = LazyTransform::new_fallible(tracked_transform(&log));
+    for i in 0..100 {
+        lt.set_source(Tracked::new(i, &log));
+    }
+    drop(lt);
+    assert_eq!(log.created(), 100);
+    log.assert_dropped_once();
+}
+
+#[test]
+fn drop_transformed() {
+    let log = DropLog::new();
+    let lt = LazyTransform::new_fallible(tracked_transform(&log));
+    for i in 0..100 {
+        lt.set_source(Tracked::new(i, &log));
+        lt.with_transformed(|value| assert!(value.payload <= i * 10));
+        // Clones handed out are dropped by us.
+        if let Some(Err(error)) = lt.get_transformed_result() {
+            assert_eq!(error.payload, i * 10);
+        }
+    }
+    // Leave a value, an error and a pending source behind.
+    assert!(lt.has_value() && lt.last_error().is_some());
+    lt.set_source(Tracked::new(1000, &log));
+    drop(lt);
+    log.assert_dropped_once();
+}
+
+#[test]
+fn drop_retried() {
+    let log = DropLog::new();
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_secs(0),
+        max_backoff: Duration::from_secs(0),
+    };
+    let lt = LazyTransform::new_fallible(tracked_transform(&log))
+        .with_retry(policy);
+    for i in 0..30 {
+        lt.set_source(Tracked::new(i, &log));
+        lt.get_transformed();
+        if i % 2 == 0 {
+            lt.get_transformed();
+        }
+    }
+    drop(lt);
+    log.assert_dropped_once();
+}
+
+#[test]
+fn drop_retained_source() {
+    let log = DropLog::new();
+    let lt = LazyTransform::new_dyn(Fallible(tracked_transform(&log)))
+        .with_retained_source();
+    for i in 0..30 {
+        lt.set_source(Tracked::new(i, &log));
+        lt.with_transformed(|_| ());
+        if i % 5 == 0 {
+            lt.set_transform_fn_and_rerun(
+                Box::new(Fallible(tracked_transform(&log))));
+        }
+    }
+    // Leave an uninstalled transform function behind.
+    lt.set_transform_fn(Box::new(Fallible(tracked_transform(&log))));
+    drop(lt);
+    log.assert_dropped_once();
+}
+
+#[test]
+fn drop_lossless_queue() {
+    let log = DropLog::new();
+    let lt = LazyTransform::new_fallible(tracked_transform(&log))
+        .with_lossless(None);
+    for i in 0..30 {
+        lt.set_source(Tracked::new(i, &log));
+        if i % 7 == 0 {
+            lt.with_transformed(|_| ());
+        }
+    }
+    // Leave a queue of sources behind.
+    assert!(lt.has_pending_source());
+    drop(lt);
+    log.assert_dropped_once();
+}
+
+#[test]
+fn drop_threaded() {
+    const ITERS: u64 = 20_000;
+    let log = DropLog::new();
+    let lt = Arc::new(LazyTransform::new_fallible(tracked_transform(&log)));
+    let producers: Vec<_> = (0..4).map(|p| thread::spawn({
+        let (lt, log) = (Arc::clone(&lt), Arc::clone(&log));
+        move || {
+            for i in 0..ITERS {
+                lt.set_source(Tracked::new(i * 4 + p, &log));
+            }
+        }
+    })).collect();
+    let consumers: Vec<_> = (0..8).map(|c| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for _ in 0..ITERS {
+                if c % 2 == 0 {
+                    lt.get_transformed();
+                } else {
+                    lt.with_transformed(|value| value.payload);
+                }
+            }
+        }
+    })).collect();
+    for thread in producers.into_iter().chain(consumers) {
+        thread.join().unwrap();
+    }
+    drop(lt);
+    log.assert_dropped_once();
+}
+
+#[test]
+fn drop_eager() {
+    let log = DropLog::new();
+    let lt = LazyTransform::new_fallible(tracked_transform(&log))
+        .into_eager(TransformerThread::new());
+    for i in 0..1000 {
+        lt.set_source(Tracked::new(i, &log));
+    }
+    // Tasks still queued keep the instance alive until they've run.
+    drop(lt);
+    log.assert_dropped_once();
+}
+
+#[test]
+fn recycle_dropped_sources() {
+    let log = DropLog::new();
+    let recycled = Arc::new(Mutex::new(Vec::new()));
+    let consumed = Arc::new(Mutex::new(Vec::new()));
+    let lt = LazyTransform::new({
+        let consumed = Arc::clone(&consumed);
+        move |source: Tracked| {
+            consumed.lock().unwrap().push(source.payload);
+            Some(source.payload)
+        }
+    }).on_source_dropped({
+        let recycled = Arc::clone(&recycled);
+        move |source: Tracked| recycled.lock().unwrap().push(source.payload)
+    });
+    for i in 0..100 {
+        lt.set_source(Tracked::new(i, &log));
+        if i % 10 == 0 {
+            lt.get_transformed();
+        }
+    }
+    // Superseded sources are handed back by set_source right away.
+    assert_eq!(recycled.lock().unwrap().len(), 89);
+    drop(lt);
+    log.assert_dropped_once();
+    let mut all: Vec<u64> = recycled.lock().unwrap().iter()
+        .chain(consumed.lock().unwrap().iter()).cloned().collect();
+    all.sort();
+    assert_eq!(all, (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn recycle_retained_sources() {
+    let log = DropLog::new();
+    let recycled = Arc::new(Mutex::new(0));
+    let lt = LazyTransform::new_fallible(tracked_transform(&log))
+        .with_retry(RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_secs(0),
+            max_backoff: Duration::from_secs(0),
+        })
+        .with_retained_source()
+        .on_source_dropped({
+            let recycled = Arc::clone(&recycled);
+            move |_| *recycled.lock().unwrap() += 1
+        });
+    for i in 0..30 {
+        lt.set_source(Tracked::new(i, &log));
+        lt.get_transformed();
+        lt.get_transformed();
+    }
+    drop(lt);
+    log.assert_dropped_once();
+    // Each attempt transforms a clone and retains another, so what comes
+    // back is the 30 originals plus a retained copy per attempt, two for
+    // each of the 10 failing sources.
+    assert_eq!(*recycled.lock().unwrap(), 70);
+}
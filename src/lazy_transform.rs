@@ -1,109 +1,2538 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::any::Any;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe, RefUnwindSafe};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use coco::epoch::{self, Atomic, Owned, Ptr, Scope};
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned, Shared};
+use futures_core::Stream;
 
 #[derive(Debug)]
-pub struct LazyTransform<T, S, FN> {
-    transform_fn: FN,
-    source: Atomic<S>,
-    value: Atomic<T>,
+pub struct LazyTransform<T, S, FN: Transform<S, T>> {
+    // Only accessed by the holder of TRANSFORM_LOCK.
+    transform_fn: UnsafeCell<FN>,
+    // Replacement for TRANSFORM_FN, installed by the next transform.
+    next_transform_fn: Atomic<FN>,
+    source: Atomic<Pending<S>>,
+    // Copy of the last source taken by a transform, kept to re-run it when
+    // the transform function is replaced.
+    retain_source: Option<fn(&S) -> S>,
+    last_source: Atomic<Retained<S>>,
+    source_dropped: Option<SourceHook<S>>,
+    coalesce: Coalesce<S>,
+    // Sources queued in lossless mode and not yet taken by a transform.
+    queued: AtomicUsize,
+    // Highest version accepted by set_source_versioned, or 0 if none, and
+    // whether 0 itself has been.  Likewise the highest taken by a
+    // transform, see Pending::version.
+    latest_version: AtomicU64,
+    zero_version_taken: AtomicBool,
+    taken_version: AtomicU64,
+    // Number of set_value calls, which supersede sources published before
+    // them, see Pending::resets.
+    value_resets: AtomicU64,
+    value: Atomic<Published<T>>,
+    error: Atomic<Failure<FN::Error>>,
+    // Last generation handed out, see Published.
+    generation: AtomicU64,
+    // Number of set_source calls so far, bumped after the source is in
+    // place.  Lets readers tell cheaply that nothing can have changed.
+    sources_published: AtomicU64,
+    // Number of times the lock holder has taken sources out, and the last
+    // of those takes whose sources have been dealt with.  Lets
+    // get_transformed_fresh tell when the sources published before it was
+    // called are reflected in the value.
+    takes: AtomicU64,
+    takes_done: AtomicU64,
+    // Set by with_staleness_tracking, as the point from which
+    // IN_FLIGHT_SINCE is measured.  Sources are only timestamped if set.
+    staleness_base: Option<Instant>,
+    // Pending::published_at of the sources taken by the transform in
+    // progress, in nanoseconds since STALENESS_BASE, or NOT_IN_FLIGHT.
+    in_flight_since: AtomicU64,
+    staleness: StalenessCounters,
     transform_lock: LightLock,
+    waiters: Waiters,
+    retry: Option<Retry<S>>,
+    retries: AtomicU64,
+    abandoned: AtomicU64,
+    panic_policy: PanicPolicy,
+    panic: Atomic<Failure<String>>,
+    poisoned: AtomicBool,
+    // How long values stay valid, and what happens then.
+    ttl: Option<(Duration, Expiry)>,
+    // Generation of the last expired value rerun under Expiry::Rerun.
+    rerun_for: AtomicU64,
+    // Set by into_eager and into_adaptive, in which case transforms run on
+    // the executor instead of in readers.
+    eager: Option<Eager>,
+    // None for SystemClock.
+    clock: Option<ClockRef>,
 }
 
-impl<T: Clone, S, FN: Fn(S) -> Option<T>> LazyTransform<T, S, FN> {
-    pub fn new(transform_fn: FN) -> LazyTransform<T, S, FN> {
+// The transformation from source to value.  Implemented for closures
+// returning Option<T>, whose failures carry no information, and by Fallible
+// for closures returning Result<T, E>.  Only one transform runs at a time,
+// so it may mutate state of its own.
+pub trait Transform<S, T> {
+    type Error;
+
+    fn transform(&mut self, source: S) -> Result<T, Self::Error>;
+
+    // Like transform, but with access to the current value, if any, which
+    // can be used to avoid rebuilding unchanged parts.  This is what
+    // LazyTransform calls.
+    fn transform_from(&mut self, source: S, _previous: Option<&T>)
+                      -> Result<T, Self::Error> {
+        self.transform(source)
+    }
+}
+
+impl<S, T, F: FnMut(S) -> Option<T>> Transform<S, T> for F {
+    type Error = ();
+
+    fn transform(&mut self, source: S) -> Result<T, ()> {
+        self(source).ok_or(())
+    }
+}
+
+// Boxed transform, allowing set_transform_fn to switch between functions
+// of different types.  See LazyTransform::new_dyn.
+pub type DynTransform<S, T, E> =
+    Box<dyn Transform<S, T, Error = E> + Send + Sync>;
+
+impl<S, T, E> Transform<S, T> for DynTransform<S, T, E> {
+    type Error = E;
+
+    fn transform(&mut self, source: S) -> Result<T, E> {
+        (**self).transform(source)
+    }
+
+    fn transform_from(&mut self, source: S, previous: Option<&T>)
+                      -> Result<T, E> {
+        (**self).transform_from(source, previous)
+    }
+}
+
+// Adapter for transform functions that report why they failed, see
+// LazyTransform::new_fallible.
+#[derive(Debug, Clone, Copy)]
+pub struct Fallible<F>(pub F);
+
+impl<S, T, E, F: FnMut(S) -> Result<T, E>> Transform<S, T> for Fallible<F> {
+    type Error = E;
+
+    fn transform(&mut self, source: S) -> Result<T, E> {
+        (self.0)(source)
+    }
+}
+
+// Adapter for transform functions that are also given the current value,
+// see LazyTransform::new_incremental.
+#[derive(Debug, Clone, Copy)]
+pub struct Incremental<F>(pub F);
+
+impl<S, T, F> Transform<S, T> for Incremental<F>
+    where F: FnMut(S, Option<&T>) -> Option<T>
+{
+    type Error = ();
+
+    fn transform(&mut self, source: S) -> Result<T, ()> {
+        (self.0)(source, None).ok_or(())
+    }
+
+    fn transform_from(&mut self, source: S, previous: Option<&T>)
+                      -> Result<T, ()> {
+        (self.0)(source, previous).ok_or(())
+    }
+}
+
+// A published source waiting to be transformed.
+#[derive(Debug)]
+struct Pending<S> {
+    source: S,
+    // Number of failed attempts to transform SOURCE so far, and when the
+    // next one is due.  Only used with a retry policy.
+    attempts: u32,
+    retry_at: Option<Instant>,
+    // For set_source_versioned, the sequence number.
+    version: Option<u64>,
+    // LazyTransform::value_resets as of publishing.  Should set_value be
+    // called afterwards, the source is dropped rather than transformed,
+    // whenever that happens.
+    resets: u64,
+    // When the oldest source not reflected in the value that this one
+    // stands for was published: this one, or a source it replaced or is
+    // chained to.  None for sources that weren't published, and without
+    // staleness tracking.
+    published_at: Option<Instant>,
+    // In lossless mode, the source published before this one, if it is
+    // still waiting as well.  Owned by this node.
+    older: Atomic<Pending<S>>,
+}
+
+impl<S> Pending<S> {
+    fn new(source: S) -> Pending<S> {
+        Pending {
+            source,
+            attempts: 0,
+            retry_at: None,
+            version: None,
+            resets: 0,
+            published_at: None,
+            older: Atomic::null(),
+        }
+    }
+
+    // Set PUBLISHED_AT for a source published at NOW, which is about to
+    // take the place of CURRENT.  NOW is None without staleness tracking.
+    fn published(&mut self, now: Option<Instant>, current: Shared<Pending<S>>) {
+        let now = match now {
+            Some(now) => now,
+            None => return,
+        };
+        let since = unsafe { current.as_ref() }
+            .and_then(|current| current.published_at);
+        self.published_at = Some(since.map_or(now, |since| since.min(now)));
+    }
+
+    fn is_due(&self) -> bool {
+        self.retry_at.is_none_or(|retry_at| Instant::now() >= retry_at)
+    }
+}
+
+// Copy of a source kept by with_retained_source, along with
+// Pending::resets of the original.
+#[derive(Debug)]
+struct Retained<S> {
+    source: S,
+    resets: u64,
+}
+
+// How set_source treats a source that is still waiting to be transformed.
+// Except with Replace, sources are chained as they come in, and the chain
+// is dealt with by the next transform.
+enum Coalesce<S> {
+    // Drop it, the default.
+    Replace,
+    // Queue the new one behind it, with at most CAPACITY sources waiting.
+    Lossless { capacity: Option<usize> },
+    // Combine them, older first, and transform the result.
+    Merge(Box<dyn Fn(S, S) -> S + Send + Sync>),
+}
+
+impl<S> fmt::Debug for Coalesce<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Coalesce::Replace => f.write_str("Replace"),
+            Coalesce::Lossless { capacity } =>
+                f.debug_struct("Lossless").field("capacity", &capacity).finish(),
+            Coalesce::Merge(_) => f.write_str("Merge"),
+        }
+    }
+}
+
+// Receives sources dropped by LazyTransform, see
+// LazyTransform::on_source_dropped.  Shared with deferred destructors,
+// which may run after the LazyTransform is gone.
+struct SourceHook<S>(Arc<dyn Fn(S) + Send + Sync>);
+
+impl<S> fmt::Debug for SourceHook<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SourceHook")
+    }
+}
+
+// Runs the transforms of an eager LazyTransform, see
+// LazyTransform::into_eager.  EXECUTE may run TASK right away, queue it for
+// a thread pool, and so on, but must run it eventually.
+pub trait Executor: Send + Sync {
+    fn execute(&self, task: Task);
+}
+
+// A transform of an eager LazyTransform, as handed to an Executor.
+pub type Task = Box<dyn FnOnce() + Send>;
+
+// Executor with a dedicated thread that runs tasks one at a time.  The
+// thread exits once the TransformerThread is dropped, which for one owned
+// by a LazyTransform is when the LazyTransform is.
+#[derive(Debug)]
+pub struct TransformerThread {
+    tasks: mpsc::Sender<Task>,
+}
+
+impl TransformerThread {
+    pub fn new() -> TransformerThread {
+        let (tasks, incoming) = mpsc::channel::<Task>();
+        thread::Builder::new()
+            .name("lazy-transform".to_owned())
+            .spawn(move || {
+                for task in incoming {
+                    // A panicking task must not take the thread down with
+                    // it; what it means for the LazyTransform is up to its
+                    // PanicPolicy.
+                    let _ = panic::catch_unwind(AssertUnwindSafe(task));
+                }
+            })
+            .expect("failed to spawn transformer thread");
+        TransformerThread { tasks }
+    }
+}
+
+impl Default for TransformerThread {
+    fn default() -> TransformerThread {
+        TransformerThread::new()
+    }
+}
+
+impl Executor for TransformerThread {
+    fn execute(&self, task: Task) {
+        // The thread only goes away with the sender.
+        self.tasks.send(task).unwrap();
+    }
+}
+
+// State of an eager or adaptive LazyTransform.
+struct Eager {
+    // Hands a transform of the instance to the executor.  Built by
+    // into_eager, which knows the instance can be sent to it.
+    submit: Box<dyn Fn() + Send + Sync>,
+    // Whether a task has been handed to the executor and hasn't started
+    // yet, so there's no need for another one.
+    scheduled: AtomicBool,
+    // Whether sources currently go to the executor.  Always the case unless
+    // ADAPTIVE switches back and forth.
+    active: AtomicBool,
+    adaptive: Option<Adaptive>,
+}
+
+impl fmt::Debug for Eager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Eager")
+            .field("scheduled", &self.scheduled)
+            .field("active", &self.active)
+            .field("adaptive", &self.adaptive)
+            .finish()
+    }
+}
+
+// Source of the time used by LazyTransform for its measurements, see
+// LazyTransform::with_clock.  Tests can substitute one they control.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+// The clock used unless another one is given.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+struct ClockRef(Box<dyn Clock>);
+
+impl fmt::Debug for ClockRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+// When an adaptive LazyTransform switches between lazy and eager
+// transforms, see LazyTransform::into_adaptive.  Transforms are timed, and
+// reads counted, over a WINDOW at a time, at the end of which the decision
+// is made.  Each switch has its own thresholds, so that an instance whose
+// figures lie in between stays as it is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptivePolicy {
+    pub window: Duration,
+    // Go eager once transforms take at least EAGER_ABOVE on average, and
+    // reads come in at least at EAGER_READS_PER_SEC, as then readers keep
+    // running into expensive transforms.
+    pub eager_above: Duration,
+    pub eager_reads_per_sec: f64,
+    // Go back to lazy once transforms take at most LAZY_BELOW on average, or
+    // reads come in at most at LAZY_READS_PER_SEC, as then transforming
+    // every source is a waste.
+    pub lazy_below: Duration,
+    pub lazy_reads_per_sec: f64,
+}
+
+impl Default for AdaptivePolicy {
+    fn default() -> AdaptivePolicy {
+        AdaptivePolicy {
+            window: Duration::from_secs(1),
+            eager_above: Duration::from_micros(100),
+            eager_reads_per_sec: 1000.,
+            lazy_below: Duration::from_micros(20),
+            lazy_reads_per_sec: 100.,
+        }
+    }
+}
+
+// Figures behind the last decision of an adaptive LazyTransform, see
+// LazyTransform::adaptive_stats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveStats {
+    // Whether sources are currently transformed eagerly.
+    pub eager: bool,
+    // Number of times the mode has changed.
+    pub switches: u64,
+    // Average transform time and read rate over the last complete window,
+    // zero before the first one.
+    pub transform_time: Duration,
+    pub reads_per_sec: f64,
+}
+
+#[derive(Debug)]
+struct Adaptive {
+    policy: AdaptivePolicy,
+    // Reads in the current window, counted by each thread in the stripe
+    // picked by read_stripe, so that readers don't contend for one counter.
+    reads: [ReadCounter; READ_STRIPES],
+    switches: AtomicU64,
+    // Only updated by transforms, but also read for stats.
+    window: Mutex<Window>,
+}
+
+const READ_STRIPES: usize = 16;
+
+// Kept to a cache line of its own.
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct ReadCounter(AtomicU64);
+
+// Stripe of Adaptive::reads for the current thread.
+fn read_stripe() -> usize {
+    let token = current_thread_token() as u64;
+    (token.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize % READ_STRIPES
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    transforms: u32,
+    transform_time: Duration,
+    // Figures of the last complete window.
+    last_transform_time: Duration,
+    last_reads_per_sec: f64,
+}
+
+// What to do when the transform function panics.  In all cases the source
+// being transformed is lost (unless retried) and the previous value stays in
+// place.
+//
+// The transform function may call back into its own LazyTransform.  Reads
+// such as get_transformed and load then return the value about to be
+// replaced, without transforming.  Calls that would wait for the transform
+// to finish, such as wait_for_value and get_transformed_fresh, panic, and
+// so does set_value, which would discard the value being computed.  Such
+// panics are dealt with like any other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPolicy {
+    // Let the panic unwind into the reader that happened to run the
+    // transform.  This is the default.
+    Propagate,
+    // Treat the panic like a failed transform, which is subject to the retry
+    // policy, and keep its message for last_panic().
+    Catch,
+    // Propagate the panic, and make all subsequent reads and waits on the
+    // instance panic as well.
+    Poison,
+}
+
+// How to retry sources whose transform failed, see
+// LazyTransform::with_retry.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // Total number of attempts, including the first one, before giving up on
+    // a source.
+    pub max_attempts: u32,
+    // Delay before the first retry, doubled with each subsequent one up to
+    // MAX_BACKOFF.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    fn backoff(&self, failed_attempts: u32) -> Duration {
+        let factor = 1u32.checked_shl(failed_attempts - 1).unwrap_or(u32::MAX);
+        self.initial_backoff.checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Retry<S> {
+    policy: RetryPolicy,
+    // The transform function consumes the source, so a copy must be made
+    // beforehand to have something to retry.
+    clone_source: fn(&S) -> S,
+}
+
+// Counters maintained under a retry policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetryStats {
+    // Transform attempts of a source that has failed before.
+    pub retries: u64,
+    // Sources given up on after failing RetryPolicy::max_attempts times.
+    // Failed sources superseded by set_source aren't counted.
+    pub abandoned: u64,
+}
+
+// Counters kept by get_transformed_within, see
+// LazyTransform::staleness_stats.  Staleness is how long the oldest source
+// not yet reflected in the value has been waiting, pending or being
+// transformed, when a read comes in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StalenessStats {
+    // Reads content with the current value, and reads that found it too
+    // stale and waited for or ran a transform.
+    pub within_bound: u64,
+    pub exceeded: u64,
+    // Largest staleness seen, and the total over all reads, to compute the
+    // average from.
+    pub max_staleness: Duration,
+    pub total_staleness: Duration,
+}
+
+// LazyTransform::in_flight_since when no transform is in progress.
+const NOT_IN_FLIGHT: u64 = u64::MAX;
+
+#[derive(Debug)]
+struct StalenessCounters {
+    within_bound: AtomicU64,
+    exceeded: AtomicU64,
+    // In nanoseconds.
+    max_staleness: AtomicU64,
+    total_staleness: AtomicU64,
+}
+
+impl StalenessCounters {
+    const fn new() -> StalenessCounters {
+        StalenessCounters {
+            within_bound: AtomicU64::new(0),
+            exceeded: AtomicU64::new(0),
+            max_staleness: AtomicU64::new(0),
+            total_staleness: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, staleness: Duration, within_bound: bool) {
+        let counter = if within_bound {
+            &self.within_bound
+        } else {
+            &self.exceeded
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let nanos = staleness.as_nanos().min(u64::MAX as u128) as u64;
+        self.max_staleness.fetch_max(nanos, Ordering::Relaxed);
+        self.total_staleness.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+// What reads return once the value has outlived its TTL, see
+// LazyTransform::with_ttl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    // Nothing, as if there were no value, until a new one is installed.
+    Discard,
+    // The expired value.  LazyTransform::get_with_expiry flags it as such.
+    KeepStale,
+    // A new value, transformed from a copy of the last source kept by
+    // with_retained_source.  The first read after expiry runs the
+    // transform, or in eager mode hands it to the executor, and reads
+    // return the expired value until it's done.  Should the transform fail,
+    // the expired value stays until the next source.
+    Rerun,
+}
+
+// Point-in-time view of a LazyTransform, see
+// LazyTransform::snapshot_state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateSnapshot {
+    pub has_pending_source: bool,
+    pub is_transforming: bool,
+    pub has_value: bool,
+    // Generation of the current value, 0 if there is none.
+    pub generation: u64,
+}
+
+// A transformed value together with the generation under which it was
+// published.  Each transform, successful or not, and each value set
+// directly is assigned the next generation, starting with 1, so 0 can be
+// used to mean "nothing seen yet".
+#[derive(Debug)]
+struct Published<T> {
+    generation: u64,
+    // For the TTL, see LazyTransform::with_ttl.  None without one.
+    installed_at: Option<Instant>,
+    // Whether VALUE came from the transform function, rather than from
+    // set_value and the like, so that Expiry::Rerun can renew it.
+    transformed: bool,
+    value: T,
+}
+
+// An error returned by the transform function, and the generation that
+// would have been published had it succeeded.
+#[derive(Debug)]
+struct Failure<E> {
+    generation: u64,
+    error: E,
+}
+
+impl<T, S, FN: FnMut(S) -> Option<T>> LazyTransform<T, S, FN> {
+    // TRANSFORM_FN may be FnMut: it is only called by the thread holding the
+    // transform lock, so it can keep state such as caches across calls
+    // without locking of its own.  This is a const fn, so with a fn pointer
+    // for TRANSFORM_FN, the LazyTransform can be a static.
+    pub const fn new(transform_fn: FN) -> LazyTransform<T, S, FN> {
+        LazyTransform::with_transform(transform_fn)
+    }
+
+    // Like new, but start out with VALUE, so that there is always a value
+    // and get() can be used.  See with_initial_value for the other
+    // constructors.
+    pub fn with_value(transform_fn: FN, value: T) -> LazyTransform<T, S, FN> {
+        LazyTransform::new(transform_fn).with_initial_value(value)
+    }
+
+    // Like new, but start out with SOURCE pending.  See with_initial_source
+    // for the other constructors.
+    pub fn with_source(transform_fn: FN, source: S) -> LazyTransform<T, S, FN> {
+        LazyTransform::new(transform_fn).with_initial_source(source)
+    }
+}
+
+impl<T, E, S, F: FnMut(S) -> Result<T, E>> LazyTransform<T, S, Fallible<F>> {
+    // Like new, but TRANSFORM_FN returns Result.  A failed transform leaves
+    // the previous value in place and records the error, which can be
+    // retrieved with last_error() and get_transformed_result().
+    pub const fn new_fallible(transform_fn: F)
+                              -> LazyTransform<T, S, Fallible<F>> {
+        LazyTransform::with_transform(Fallible(transform_fn))
+    }
+}
+
+impl<T, S, F> LazyTransform<T, S, Incremental<F>>
+    where F: FnMut(S, Option<&T>) -> Option<T>
+{
+    // Like new, but TRANSFORM_FN also receives the current value, if any,
+    // to build the new one from.  The reference stays valid for the whole
+    // call, regardless of what readers are doing.
+    pub const fn new_incremental(transform_fn: F)
+                           -> LazyTransform<T, S, Incremental<F>> {
+        LazyTransform::with_transform(Incremental(transform_fn))
+    }
+}
+
+impl<T, E, S> LazyTransform<T, S, DynTransform<S, T, E>> {
+    // Like new or new_fallible, but box the transform function, so that
+    // set_transform_fn can later replace it with one of a different type.
+    pub fn new_dyn<F>(transform_fn: F)
+                      -> LazyTransform<T, S, DynTransform<S, T, E>>
+        where F: Transform<S, T, Error = E> + Send + Sync + 'static
+    {
+        LazyTransform::with_transform(Box::new(transform_fn))
+    }
+}
+
+impl<T, I, FN: Transform<Vec<I>, T>> LazyTransform<T, Vec<I>, FN> {
+    // Coalesce sources by concatenating them, so the transform function
+    // receives everything set since it last ran.  See with_merge.
+    pub fn with_append(self) -> LazyTransform<T, Vec<I>, FN> {
+        self.with_merge(|mut older: Vec<I>, newer| {
+            older.extend(newer);
+            older
+        })
+    }
+
+    // Set a source consisting of a single ITEM, to be appended to those
+    // still waiting under with_append.
+    pub fn push_source(&self, item: I) {
+        self.set_source(vec![item]);
+    }
+}
+
+// The transform function is only used under the transform lock, so it
+// needn't be Sync.
+unsafe impl<T, S, FN> Sync for LazyTransform<T, S, FN>
+    where T: Send + Sync, S: Send + Sync, FN: Transform<S, T> + Send,
+          FN::Error: Send + Sync {}
+
+unsafe impl<T, S, FN> Send for LazyTransform<T, S, FN>
+    where T: Send + Sync, S: Send + Sync, FN: Transform<S, T> + Send,
+          FN::Error: Send + Sync {}
+
+// UnsafeCell would otherwise rule this out, and PanicPolicy takes care of a
+// panic in the transform function.
+impl<T, S, FN> RefUnwindSafe for LazyTransform<T, S, FN>
+    where T: RefUnwindSafe, S: RefUnwindSafe,
+          FN: Transform<S, T> + RefUnwindSafe, FN::Error: RefUnwindSafe {}
+
+impl<T, S, FN: Transform<S, T>> LazyTransform<T, S, FN> {
+    const fn with_transform(transform_fn: FN) -> LazyTransform<T, S, FN> {
         LazyTransform {
-            transform_fn: transform_fn,
+            transform_fn: UnsafeCell::new(transform_fn),
+            next_transform_fn: Atomic::null(),
             source: Atomic::null(),
+            retain_source: None,
+            last_source: Atomic::null(),
+            source_dropped: None,
+            coalesce: Coalesce::Replace,
+            queued: AtomicUsize::new(0),
+            latest_version: AtomicU64::new(0),
+            zero_version_taken: AtomicBool::new(false),
+            taken_version: AtomicU64::new(0),
+            value_resets: AtomicU64::new(0),
             value: Atomic::null(),
+            error: Atomic::null(),
+            generation: AtomicU64::new(0),
+            sources_published: AtomicU64::new(0),
+            takes: AtomicU64::new(0),
+            takes_done: AtomicU64::new(0),
+            staleness_base: None,
+            in_flight_since: AtomicU64::new(NOT_IN_FLIGHT),
+            staleness: StalenessCounters::new(),
             transform_lock: LightLock::new(),
+            waiters: Waiters::new(),
+            retry: None,
+            retries: AtomicU64::new(0),
+            abandoned: AtomicU64::new(0),
+            panic_policy: PanicPolicy::Propagate,
+            panic: Atomic::null(),
+            poisoned: AtomicBool::new(false),
+            ttl: None,
+            rerun_for: AtomicU64::new(0),
+            eager: None,
+            clock: None,
+        }
+    }
+
+    // Start out with VALUE, so that there is always a value and get() can
+    // be used.
+    pub fn with_initial_value(self, value: T) -> LazyTransform<T, S, FN> {
+        self.set_value(value);
+        self
+    }
+
+    // Start out with SOURCE pending.
+    pub fn with_initial_source(self, source: S) -> LazyTransform<T, S, FN> {
+        self.set_source(source);
+        self
+    }
+
+    // Choose what happens when the transform function panics, see
+    // PanicPolicy.
+    pub fn with_panic_policy(mut self, policy: PanicPolicy)
+                             -> LazyTransform<T, S, FN> {
+        self.panic_policy = policy;
+        self
+    }
+
+    // Return the message of the last panic caught under PanicPolicy::Catch
+    // or Poison, along with the generation of the failed transform.
+    pub fn last_panic(&self) -> Option<(u64, String)> {
+        let guard = &epoch::pin();
+        unsafe {
+            self.panic.load(Ordering::Acquire, guard).as_ref()
+                .map(|failure| (failure.generation, failure.error.clone()))
+        }
+    }
+
+    // Whether a panic in the transform function has poisoned the instance
+    // under PanicPolicy::Poison.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    // Keep sources whose transform failed pending, so that subsequent reads
+    // retry them according to POLICY, unless a newer source is set first.
+    // Each attempt transforms a clone of the source.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> LazyTransform<T, S, FN>
+        where S: Clone
+    {
+        assert!(policy.max_attempts > 0, "max_attempts must be positive");
+        assert!(matches!(self.coalesce, Coalesce::Replace),
+                "retry is only supported when replacing sources");
+        self.retry = Some(Retry { policy, clone_source: S::clone });
+        self
+    }
+
+    // Transform every source, rather than only the latest one.  Sources set
+    // while others are waiting are queued, and the next reader to transform
+    // takes the whole queue and transforms it in order, publishing each
+    // value.  With a CAPACITY, set_source blocks while that many sources
+    // are waiting, see set_source_blocking.  Can't be combined with retry.
+    // Sources set beforehand, as by with_initial_source, take up room in
+    // the queue like any other.
+    pub fn with_lossless(mut self, capacity: Option<usize>)
+                         -> LazyTransform<T, S, FN> {
+        assert!(capacity != Some(0), "capacity must be positive");
+        assert!(self.retry.is_none(),
+                "retry is only supported when replacing sources");
+        self.coalesce = Coalesce::Lossless { capacity };
+        // We own the instance, so no other thread can be at the chain.
+        let guard = unsafe { epoch::unprotected() };
+        let mut queued = 0;
+        let mut node = self.source.load(Ordering::Relaxed, guard);
+        while let Some(pending) = unsafe { node.as_ref() } {
+            queued += 1;
+            node = pending.older.load(Ordering::Relaxed, guard);
+        }
+        self.queued.store(queued, Ordering::Relaxed);
+        self
+    }
+
+    // Rather than dropping a source still waiting when a new one is set,
+    // combine the two with MERGE(older, newer).  Sources are chained as they
+    // come in and merged by the next transform, so publishing stays
+    // lock-free and a run of sources costs one transform.  Can't be
+    // combined with retry.
+    pub fn with_merge<M>(mut self, merge: M) -> LazyTransform<T, S, FN>
+        where M: Fn(S, S) -> S + Send + Sync + 'static
+    {
+        assert!(self.retry.is_none(),
+                "retry is only supported when replacing sources");
+        self.coalesce = Coalesce::Merge(Box::new(merge));
+        self
+    }
+
+    // Hand sources that are dropped without being transformed to HOOK, to
+    // recycle their buffers for instance.  That includes sources superseded
+    // by set_source, retry copies, retained sources and those left over when
+    // the LazyTransform is dropped.  Sources given to the transform function
+    // are its own, so it can recycle them itself.  HOOK may be called from
+    // any thread, including from within set_source.
+    pub fn on_source_dropped<H>(mut self, hook: H) -> LazyTransform<T, S, FN>
+        where H: Fn(S) + Send + Sync + 'static
+    {
+        self.source_dropped = Some(SourceHook(Arc::new(hook)));
+        self
+    }
+
+    fn drop_source(&self, source: S) {
+        if let Some(SourceHook(ref hook)) = self.source_dropped {
+            hook(source);
+        }
+    }
+
+    // Drop the source in NODE once no other thread can be looking at it.
+    unsafe fn defer_drop_source(&self, node: Shared<Retained<S>>,
+                                guard: &Guard) {
+        match self.source_dropped {
+            Some(SourceHook(ref hook)) => {
+                let hook = Arc::clone(hook);
+                guard.defer_unchecked(
+                    move || hook(node.into_owned().into_box().source));
+            }
+            None => guard.defer_destroy(node),
+        }
+    }
+
+    // Transform sources eagerly, as soon as they are set, rather than in
+    // whichever reader comes along first.  The transforms are handed to
+    // EXECUTOR, such as a TransformerThread, and readers only ever load the
+    // current value, so they never pay for a transform.  Tasks given to the
+    // executor hold on to the instance, hence the Arc.  Under
+    // PanicPolicy::Propagate, a panic in the transform function unwinds into
+    // the executor.  Can't be combined with retry.
+    pub fn into_eager<E>(self, executor: E) -> Arc<LazyTransform<T, S, FN>>
+        where E: Executor + 'static,
+              T: Send + Sync + 'static, S: Send + Sync + 'static,
+              FN: Send + 'static, FN::Error: Send + Sync + 'static
+    {
+        self.into_executor(executor, None)
+    }
+
+    // Like into_eager, but start out lazy, and switch between lazy and
+    // eager transforms as POLICY decides based on how long transforms take
+    // and how often the value is read.  Reads are counted by
+    // get_transformed and the like, and by readers when a source has been
+    // published since their last read.  Time is taken from the clock set
+    // by with_clock, which must come first.
+    pub fn into_adaptive<E>(self, executor: E, policy: AdaptivePolicy)
+                            -> Arc<LazyTransform<T, S, FN>>
+        where E: Executor + 'static,
+              T: Send + Sync + 'static, S: Send + Sync + 'static,
+              FN: Send + 'static, FN::Error: Send + Sync + 'static
+    {
+        self.into_executor(executor, Some(policy))
+    }
+
+    fn into_executor<E>(mut self, executor: E, policy: Option<AdaptivePolicy>)
+                        -> Arc<LazyTransform<T, S, FN>>
+        where E: Executor + 'static,
+              T: Send + Sync + 'static, S: Send + Sync + 'static,
+              FN: Send + 'static, FN::Error: Send + Sync + 'static
+    {
+        assert!(self.retry.is_none(),
+                "retry is not supported in eager mode");
+        let adaptive = policy.map(|policy| Adaptive {
+            policy,
+            reads: Default::default(),
+            switches: AtomicU64::new(0),
+            window: Mutex::new(Window {
+                start: self.now(),
+                transforms: 0,
+                transform_time: Duration::from_secs(0),
+                last_transform_time: Duration::from_secs(0),
+                last_reads_per_sec: 0.,
+            }),
+        });
+        let lt = Arc::new_cyclic(|this: &Weak<LazyTransform<T, S, FN>>| {
+            let this = Weak::clone(this);
+            let submit = move || {
+                // Only fails while the instance is being dropped.
+                if let Some(lt) = this.upgrade() {
+                    executor.execute(Box::new(move || lt.transform_eagerly()));
+                }
+            };
+            self.eager = Some(Eager {
+                submit: Box::new(submit),
+                scheduled: AtomicBool::new(false),
+                active: AtomicBool::new(adaptive.is_none()),
+                adaptive,
+            });
+            self
+        });
+        // Constructed with a source, which is waiting for us.
+        if lt.has_pending_source() {
+            lt.schedule_transform();
+        }
+        lt
+    }
+
+    // Whether sources are currently transformed eagerly, see into_eager and
+    // into_adaptive.
+    pub fn is_eager(&self) -> bool {
+        self.eager.as_ref()
+            .is_some_and(|eager| eager.active.load(Ordering::SeqCst))
+    }
+
+    // For an instance created by into_adaptive, return the current mode
+    // and the measurements that led to it.
+    pub fn adaptive_stats(&self) -> Option<AdaptiveStats> {
+        let eager = self.eager.as_ref()?;
+        let adaptive = eager.adaptive.as_ref()?;
+        let window = adaptive.window.lock().unwrap();
+        Some(AdaptiveStats {
+            eager: eager.active.load(Ordering::SeqCst),
+            switches: adaptive.switches.load(Ordering::Relaxed),
+            transform_time: window.last_transform_time,
+            reads_per_sec: window.last_reads_per_sec,
+        })
+    }
+
+    // Let values expire TTL after they're installed, measured with the clock
+    // set by with_clock.  Reads of an expired value are then dealt with as
+    // EXPIRY says.  Expiry::Rerun requires with_retained_source first, and
+    // treats values installed by set_value and the like as KeepStale does.
+    // Under Expiry::Discard, has_value() and snapshot_state() report no
+    // value once it expires, and get() panics, even on an instance created
+    // with_value.  Handles
+    // returned by reader() can't tell expiry from the cache, so they go
+    // back to the LazyTransform on every read.
+    pub fn with_ttl(mut self, ttl: Duration, expiry: Expiry)
+                    -> LazyTransform<T, S, FN> {
+        assert!(expiry != Expiry::Rerun || self.retain_source.is_some(),
+                "Expiry::Rerun requires with_retained_source");
+        self.ttl = Some((ttl, expiry));
+        // A value set beforehand, as by with_value, expires TTL from now.
+        if let Some(mut published) = unsafe { take_owned(&self.value) } {
+            published.installed_at = Some(self.now());
+            self.value.store(published, Ordering::Relaxed);
+        }
+        self
+    }
+
+    fn is_expired(&self, published: &Published<T>) -> bool {
+        match (self.ttl, published.installed_at) {
+            (Some((ttl, _)), Some(installed_at)) =>
+                self.now().saturating_duration_since(installed_at) >= ttl,
+            _ => false,
+        }
+    }
+
+    // Return what reads should see of PUBLISHED, the current value, given
+    // the TTL.
+    fn apply_ttl<'g>(&self, published: Option<&'g Published<T>>,
+                     guard: &'g Guard) -> Option<&'g Published<T>> {
+        let expiry = match (published, self.ttl) {
+            (Some(published), Some((_, expiry)))
+                if self.is_expired(published) => expiry,
+            _ => return published,
+        };
+        let published = published.unwrap();
+        match expiry {
+            Expiry::Discard => None,
+            // Values that weren't transformed have no source to rerun.
+            Expiry::KeepStale => Some(published),
+            Expiry::Rerun if !published.transformed => Some(published),
+            Expiry::Rerun => {
+                // Once per expired value, so that a failing transform isn't
+                // rerun on every read.
+                let generation = published.generation;
+                if self.rerun_for.fetch_max(generation, Ordering::Relaxed)
+                    < generation && self.rerun_last_source() {
+                    self.transform_on_read(guard);
+                }
+                unsafe { self.value.load(Ordering::Acquire, guard).as_ref() }
+            }
+        }
+    }
+
+    // Use CLOCK instead of SystemClock for measurements.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C)
+                                          -> LazyTransform<T, S, FN> {
+        self.clock = Some(ClockRef(Box::new(clock)));
+        self
+    }
+
+    fn now(&self) -> Instant {
+        match self.clock {
+            Some(ClockRef(ref clock)) => clock.now(),
+            None => Instant::now(),
+        }
+    }
+
+    // Keep track of how long sources wait to be reflected in the value, as
+    // get_transformed_within requires.  That costs a timestamp per source
+    // published, taken from the clock set by with_clock, which must come
+    // first.
+    pub fn with_staleness_tracking(mut self) -> LazyTransform<T, S, FN> {
+        self.staleness_base = Some(self.now());
+        self
+    }
+
+    // When a source published now was, if that's being tracked.
+    fn publish_time(&self) -> Option<Instant> {
+        self.staleness_base.map(|_| self.now())
+    }
+
+    fn count_read(&self) {
+        if let Some(Eager { adaptive: Some(ref adaptive), .. }) = self.eager {
+            adaptive.reads[read_stripe()].0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Account for a transform that took ELAPSED, and at the end of a window,
+    // decide whether to switch modes.  Called under the transform lock.
+    fn record_transform_time(&self, elapsed: Duration) {
+        let (eager, adaptive) = match self.eager {
+            Some(ref eager @ Eager { adaptive: Some(ref adaptive), .. }) =>
+                (eager, adaptive),
+            _ => return,
+        };
+        let now = self.now();
+        let mut window = adaptive.window.lock().unwrap();
+        window.transforms += 1;
+        window.transform_time += elapsed;
+        let span = now.saturating_duration_since(window.start);
+        if span < adaptive.policy.window {
+            return;
+        }
+        let reads: u64 = adaptive.reads.iter()
+            .map(|stripe| stripe.0.swap(0, Ordering::Relaxed))
+            .sum();
+        let transform_time = window.transform_time / window.transforms;
+        let reads_per_sec = reads as f64 / span.as_secs_f64();
+        *window = Window {
+            start: now,
+            transforms: 0,
+            transform_time: Duration::from_secs(0),
+            last_transform_time: transform_time,
+            last_reads_per_sec: reads_per_sec,
+        };
+        let policy = &adaptive.policy;
+        let is_eager = eager.active.load(Ordering::Relaxed);
+        let switch = if is_eager {
+            transform_time <= policy.lazy_below
+                || reads_per_sec <= policy.lazy_reads_per_sec
+        } else {
+            transform_time >= policy.eager_above
+                && reads_per_sec >= policy.eager_reads_per_sec
+        };
+        if switch {
+            // Sources published from now on are left to the executor, or
+            // to readers.  See transform_on_read for those published
+            // meanwhile.
+            eager.active.store(!is_eager, Ordering::SeqCst);
+            adaptive.switches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // In eager mode, have the executor transform the source just published,
+    // unless it's already been asked to.
+    fn schedule_transform(&self) {
+        let eager = match self.eager {
+            Some(ref eager) => eager,
+            None => return,
+        };
+        if eager.adaptive.is_some() {
+            // See transform_on_read.
+            atomic::fence(Ordering::SeqCst);
+            if !eager.active.load(Ordering::SeqCst) {
+                return;
+            }
+        }
+        if !eager.scheduled.swap(true, Ordering::SeqCst) {
+            (eager.submit)();
+        }
+    }
+
+    // Transform the pending source on behalf of a reader, unless in eager
+    // mode, or if the reader is the transform function itself, see
+    // PanicPolicy.
+    fn transform_on_read(&self, guard: &Guard) {
+        if self.is_eager() || self.transform_lock.is_held_by_current_thread() {
+            return;
+        }
+        self.try_transform(guard);
+        if self.is_eager() {
+            // The transform switched to eager mode, and sources published
+            // meanwhile may have gone unscheduled.  Either their producer
+            // sees the switch or we see the source.
+            atomic::fence(Ordering::SeqCst);
+            if !self.source.load(Ordering::Acquire, guard).is_null() {
+                self.schedule_transform();
+            }
+        }
+    }
+
+    // Task run by the executor in eager mode.
+    fn transform_eagerly(&self) {
+        if let Some(ref eager) = self.eager {
+            eager.scheduled.store(false, Ordering::SeqCst);
+        }
+        // A source published after the one we're about to take schedules
+        // another task, which may find the lock still held by this one.
+        // That task then gives up, and this one must notice the source
+        // after unlocking, which try_transform makes sure it can.
+        let guard = &epoch::pin();
+        // Also after a panic, which leaves the lock released.
+        let locked = panic::catch_unwind(
+            AssertUnwindSafe(|| self.try_transform(guard)));
+        if locked.as_ref().map_or(true, |&locked| locked)
+            && !self.source.load(Ordering::Acquire, guard).is_null() {
+            self.schedule_transform();
+        }
+        if let Err(payload) = locked {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    // Keep a copy of the last source handed to the transform function, so
+    // that set_transform_fn_and_rerun has something to re-run.
+    pub fn with_retained_source(mut self) -> LazyTransform<T, S, FN>
+        where S: Clone
+    {
+        self.retain_source = Some(S::clone);
+        self
+    }
+
+    // Replace the transform function.  Sources published from now on are
+    // transformed by TRANSFORM_FN, and so is the pending source if there is
+    // one.  The current value stays until a new source comes in, and once
+    // a value produced by TRANSFORM_FN is observed, values produced by the
+    // old function never are again.
+    pub fn set_transform_fn(&self, transform_fn: FN) {
+        let guard = &epoch::pin();
+        let prev = self.next_transform_fn.swap(Owned::new(transform_fn),
+                                               Ordering::AcqRel, guard);
+        // Whoever swaps a function out owns it, so it can go right away.
+        drop(unsafe { prev.try_into_owned() });
+    }
+
+    // Like set_transform_fn, but also re-transform the last source with the
+    // new function, unless a newer source is already pending.  Requires
+    // with_retained_source, and returns whether the last source was
+    // published again.
+    pub fn set_transform_fn_and_rerun(&self, transform_fn: FN) -> bool {
+        self.set_transform_fn(transform_fn);
+        self.rerun_last_source()
+    }
+
+    // Publish a copy of the retained source again, unless a newer source is
+    // already pending, and return whether it was.
+    fn rerun_last_source(&self) -> bool {
+        let clone_source = match self.retain_source {
+            Some(clone_source) => clone_source,
+            None => return false,
+        };
+        let guard = &epoch::pin();
+        let last = match unsafe {
+            self.last_source.load(Ordering::Acquire, guard).as_ref()
+        } {
+            // Retained before a set_value that hasn't got round to clearing
+            // it yet.  If set_value comes after the check, the copy is
+            // dropped rather than transformed, as it carries the stamp.
+            Some(last) if last.resets
+                != self.value_resets.load(Ordering::SeqCst) => return false,
+            Some(last) => Pending {
+                resets: last.resets,
+                published_at: self.publish_time(),
+                ..Pending::new(clone_source(&last.source))
+            },
+            None => return false,
+        };
+        // Counted beforehand, since a transform may take it out right away.
+        let lossless = matches!(self.coalesce, Coalesce::Lossless { .. });
+        if lossless {
+            self.queued.fetch_add(1, Ordering::Relaxed);
+        }
+        let rerun = match self.source.compare_exchange(
+            Shared::null(), Owned::new(last),
+            Ordering::AcqRel, Ordering::Relaxed, guard) {
+            Ok(_) => true,
+            Err(err) => {
+                self.drop_source(err.new.into_box().source);
+                false
+            }
+        };
+        if lossless && !rerun {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        if rerun {
+            self.sources_published.fetch_add(1, Ordering::Release);
+            self.waiters.notify();
+            self.schedule_transform();
+        }
+        rerun
+    }
+
+    pub fn retry_stats(&self) -> RetryStats {
+        RetryStats {
+            retries: self.retries.load(Ordering::Relaxed),
+            abandoned: self.abandoned.load(Ordering::Relaxed),
+        }
+    }
+
+    // Put a source that failed to transform back, unless it's out of
+    // attempts or has been superseded in the meantime, by a newer source or
+    // by set_value.
+    fn requeue(&self, mut pending: Pending<S>, retry: &Retry<S>,
+               guard: &Guard) {
+        if self.value_resets.load(Ordering::Acquire) != pending.resets {
+            return self.drop_source(pending.source);
+        }
+        pending.attempts += 1;
+        if pending.attempts >= retry.policy.max_attempts {
+            self.abandoned.fetch_add(1, Ordering::Relaxed);
+            return self.drop_source(pending.source);
+        }
+        let backoff = retry.policy.backoff(pending.attempts);
+        pending.retry_at = Some(Instant::now() + backoff);
+        // If the CAS fails, a newer source has arrived and the old one is
+        // dropped.
+        if let Err(err) = self.source.compare_exchange(
+            Shared::null(), Owned::new(pending),
+            Ordering::AcqRel, Ordering::Relaxed, guard) {
+            self.drop_source(err.new.into_box().source);
+        }
+        // Let waiters know when to retry.
+        self.waiters.notify();
+    }
+
+    // When the pending source, if any, is due to be retried.
+    fn retry_at(&self) -> Option<Instant> {
+        let guard = &epoch::pin();
+        unsafe {
+            self.source.load(Ordering::Acquire, guard).as_ref()
+                .and_then(|pending| pending.retry_at)
+        }
+    }
+
+    // Publish a new source.
+    pub fn set_source(&self, source: S) {
+        if let Coalesce::Lossless { capacity: Some(_) } = self.coalesce {
+            return self.set_source_blocking(source);
+        }
+        self.publish(Pending::new(source));
+    }
+
+    // Like set_source, but in lossless mode with a capacity, hand SOURCE
+    // back instead of waiting if the queue is full.
+    pub fn try_set_source(&self, source: S) -> Result<(), S> {
+        if let Coalesce::Lossless { capacity: Some(capacity) } = self.coalesce {
+            if self.queued.fetch_add(1, Ordering::Relaxed) >= capacity {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                return Err(source);
+            }
+        }
+        self.publish(Pending::new(source));
+        Ok(())
+    }
+
+    // Like set_source, but in lossless mode with a capacity, wait for room
+    // in the queue.  Queued sources are transformed by the caller if no one
+    // else is at it, so this doesn't depend on readers to make progress.
+    // In eager mode, it waits for the executor instead.
+    pub fn set_source_blocking(&self, source: S) {
+        let mut source = match self.try_set_source(source) {
+            Ok(()) => return,
+            Err(rejected) => Some(rejected),
+        };
+        self.block_until(None, || {
+            // In eager mode, the executor must be the one to transform, or
+            // it could miss sources published meanwhile.
+            self.transform_on_read(&epoch::pin());
+            match self.try_set_source(source.take().unwrap()) {
+                Ok(()) => Ok(()),
+                Err(rejected) => {
+                    source = Some(rejected);
+                    Err(None)
+                }
+            }
+        });
+    }
+
+    // Like set_source, but reject SOURCE, handing it back, unless SEQ is
+    // higher than that of any source set this way before.  Sources with a
+    // higher sequence number win regardless of the order in which they
+    // arrive, so several producers can feed the same data redundantly.
+    // Only supported when replacing sources, and not to be mixed with plain
+    // set_source, whose sources aren't ordered.
+    pub fn set_source_versioned(&self, seq: u64, source: S) -> Result<(), S> {
+        assert!(matches!(self.coalesce, Coalesce::Replace),
+                "versioned sources are only supported when replacing sources");
+        let mut latest = self.latest_version.load(Ordering::Relaxed);
+        loop {
+            if seq < latest {
+                return Err(source);
+            }
+            if seq == latest {
+                // Only 0 can be accepted without raising LATEST_VERSION,
+                // and only once.
+                if seq != 0
+                    || self.zero_version_taken.swap(true, Ordering::Relaxed) {
+                    return Err(source);
+                }
+                break;
+            }
+            match self.latest_version.compare_exchange_weak(
+                latest, seq, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => latest = current,
+            }
+        }
+        // Accepted, but a higher version may overtake us on the way in,
+        // in which case ours is dropped, as if replaced.  If that happens
+        // after the higher one was taken out, try_transform drops ours.
+        let guard = &epoch::pin();
+        let now = self.publish_time();
+        let mut node = Owned::new(Pending {
+            version: Some(seq),
+            resets: self.value_resets.load(Ordering::Acquire),
+            ..Pending::new(source)
+        });
+        let mut current = self.source.load(Ordering::Acquire, guard);
+        loop {
+            if let Some(pending) = unsafe { current.as_ref() } {
+                if pending.version.is_some_and(|version| version > seq) {
+                    self.drop_source(node.into_box().source);
+                    return Ok(());
+                }
+            }
+            node.published(now, current);
+            match self.source.compare_exchange(
+                current, node, Ordering::AcqRel, Ordering::Acquire, guard) {
+                Ok(_) => break,
+                Err(err) => {
+                    current = err.current;
+                    node = err.new;
+                }
+            }
+        }
+        if !current.is_null() {
+            self.drop_replaced(current, guard);
+        }
+        self.sources_published.fetch_add(1, Ordering::Release);
+        self.waiters.notify();
+        self.schedule_transform();
+        Ok(())
+    }
+
+    // Dispose of a node replaced in SOURCE.
+    fn drop_replaced(&self, prev: Shared<Pending<S>>, guard: &Guard) {
+        // Other threads may look at the node, but never at the source in
+        // it, so that can be dropped right away.
+        let prev_source = unsafe {
+            let prev_source = ptr::read(&prev.deref().source);
+            defer_free(guard, prev);
+            prev_source
+        };
+        self.drop_source(prev_source);
+    }
+
+    fn publish(&self, pending: Pending<S>) {
+        let guard = &epoch::pin();
+        let now = self.publish_time();
+        let mut node = Owned::new(Pending {
+            resets: self.value_resets.load(Ordering::Acquire),
+            ..pending
+        });
+        match self.coalesce {
+            // Without staleness tracking, nothing is carried over from the
+            // source replaced, so there's no need to look at it first.
+            Coalesce::Replace if now.is_none() => {
+                let prev = self.source.swap(node, Ordering::AcqRel, guard);
+                if !prev.is_null() {
+                    self.drop_replaced(prev, guard);
+                }
+            }
+            Coalesce::Replace => {
+                let mut prev = self.source.load(Ordering::Acquire, guard);
+                loop {
+                    node.published(now, prev);
+                    match self.source.compare_exchange(
+                        prev, node, Ordering::AcqRel, Ordering::Acquire, guard) {
+                        Ok(_) => break,
+                        Err(err) => {
+                            prev = err.current;
+                            node = err.new;
+                        }
+                    }
+                }
+                if !prev.is_null() {
+                    self.drop_replaced(prev, guard);
+                }
+            }
+            Coalesce::Lossless { .. } | Coalesce::Merge(_) => {
+                if let Coalesce::Lossless { capacity: None } = self.coalesce {
+                    self.queued.fetch_add(1, Ordering::Relaxed);
+                }
+                // Push onto the chain of waiting sources.  Nodes only leave
+                // the chain all at once, so there is no ABA problem.
+                let mut head = self.source.load(Ordering::Acquire, guard);
+                loop {
+                    node.older.store(head, Ordering::Relaxed);
+                    node.published(now, head);
+                    match self.source.compare_exchange(
+                        head, node, Ordering::AcqRel, Ordering::Acquire, guard) {
+                        Ok(_) => break,
+                        Err(err) => {
+                            head = err.current;
+                            node = err.new;
+                        }
+                    }
+                }
+            }
         }
+        self.sources_published.fetch_add(1, Ordering::Release);
+        // Blocked waiters transform the source themselves, unless it's up
+        // to the executor.
+        self.waiters.notify();
+        self.schedule_transform();
     }
 
-    // Publish a new source.
-    pub fn set_source(&self, source: S) {
-        epoch::pin(|scope| unsafe {
-            let source_ptr = Owned::new(source).into_ptr(&scope);
-            let prev = self.source.swap(source_ptr, Ordering::AcqRel, &scope);
+    // Transform and drop the newly published SOURCE if available, and cache
+    // the new value.  Does nothing if no new source exists, if the lock is
+    // already taken, or if a retried source isn't due yet.  In lossless
+    // mode, all queued sources are transformed in order, and in merge mode,
+    // they are merged and transformed at once.  Returns false if the lock
+    // was taken.
+    fn try_transform(&self, guard: &Guard) -> bool {
+        // A waiter that finds the lock taken goes back to sleep, so a source
+        // published meanwhile must be noticed by the lock holder once it's
+        // done.  The fences make sure that either the waiter gets the lock
+        // or the holder sees the source.
+        atomic::fence(Ordering::SeqCst);
+        match self.transform_lock.try_lock() {
+            Some(lock_guard) => {
+                self.transform_locked(guard);
+                drop(lock_guard);
+            }
+            None => return false,
+        }
+        atomic::fence(Ordering::SeqCst);
+        let due = unsafe { self.source.load(Ordering::Acquire, guard).as_ref() }
+            .is_some_and(Pending::is_due);
+        if due {
+            self.waiters.notify();
+        }
+        true
+    }
+
+    fn transform_locked(&self, guard: &Guard) {
+        // Apart from the lock holder, only set_value takes sources out,
+        // so a source seen here is normally still there for the swap,
+        // or has been replaced by a fresh one that is due.
+        let published_at = match unsafe {
+            self.source.load(Ordering::Acquire, guard).as_ref()
+        } {
+            Some(pending) if pending.is_due() => pending.published_at,
+            _ => return,
+        };
+        // Counted before the sources are taken, see is_fresh_since.
+        let take = self.takes.fetch_add(1, Ordering::SeqCst) + 1;
+        // Likewise recorded before, see staleness.  Should the source be
+        // replaced before it's taken, the replacement is no older.
+        if let (Some(base), Some(published_at)) =
+            (self.staleness_base, published_at) {
+            let nanos = published_at.saturating_duration_since(base).as_nanos();
+            self.in_flight_since.store(
+                nanos.min(NOT_IN_FLIGHT as u128 - 1) as u64, Ordering::Release);
+        }
+        let _done = TakeDone { lt: self, take };
+        let batch = self.take_pending(guard);
+        if let Coalesce::Merge(ref merge) = self.coalesce {
+            // Sources superseded by set_value stay out of the merge.  The
+            // others carry the count as of now, which none can exceed.
+            let resets = self.value_resets.load(Ordering::Acquire);
+            let mut merged = None;
+            for pending in batch {
+                if pending.resets != resets {
+                    self.drop_source(pending.source);
+                    continue;
+                }
+                merged = Some(match merged {
+                    Some(older) => merge(older, pending.source),
+                    None => pending.source,
+                });
+            }
+            if let Some(merged) = merged {
+                let merged = Pending { resets, ..Pending::new(merged) };
+                self.transform_pending(merged, guard);
+            }
+            return;
+        }
+        // A panicking transform loses the rest of the batch.
+        for pending in batch {
+            self.transform_pending(pending, guard);
+        }
+    }
+
+    // Take out all pending sources, oldest first.
+    fn take_pending(&self, guard: &Guard) -> Vec<Pending<S>> {
+        let source = self.source.swap(Shared::null(), Ordering::AcqRel, guard);
+        if source.is_null() {
+            return vec![];
+        }
+        let mut batch: Vec<Pending<S>> = vec![];
+        unsafe {
+            batch.push(ptr::read(source.as_raw()));
+            // Others may still be looking at the head, but not at the
+            // older nodes chained to it.
+            defer_free(guard, source);
+            let mut older = batch[0].older.swap(Shared::null(),
+                                                Ordering::Relaxed, guard);
+            while let Some(node) = older.try_into_owned() {
+                let node = *node.into_box();
+                older = node.older.swap(Shared::null(),
+                                        Ordering::Relaxed, guard);
+                batch.push(node);
+            }
+        }
+        if let Coalesce::Lossless { .. } = self.coalesce {
+            self.queued.fetch_sub(batch.len(), Ordering::Relaxed);
+            // Let blocked producers know there's room.
+            self.waiters.notify();
+        }
+        batch.reverse();
+        batch
+    }
+
+    // Transform a source taken out by try_transform, and publish the
+    // result unless set_value has been called since the source was
+    // published.
+    fn transform_pending(&self, pending: Pending<S>, guard: &Guard) {
+        let resets = pending.resets;
+        let superseded = || self.value_resets.load(Ordering::Acquire) != resets;
+        if superseded() {
+            return self.drop_source(pending.source);
+        }
+        if let Some(version) = pending.version {
+            // A versioned source that lost the race to a higher one.
+            if version < self.taken_version.load(Ordering::Relaxed) {
+                return self.drop_source(pending.source);
+            }
+            self.taken_version.store(version, Ordering::Relaxed);
+        }
+        if pending.attempts != 0 {
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(clone_source) = self.retain_source {
+            let retained = Owned::new(Retained {
+                source: clone_source(&pending.source),
+                resets,
+            }).into_shared(guard);
+            let prev = self.last_source.swap(retained, Ordering::SeqCst, guard);
             if !prev.is_null() {
-                scope.defer_drop(prev);
+                unsafe { self.defer_drop_source(prev, guard); }
             }
-        });
+            // set_value clears the retained source after bumping
+            // VALUE_RESETS, so if it came in meanwhile, ours must go too.
+            if self.value_resets.load(Ordering::SeqCst) != resets
+                && self.last_source.compare_exchange(
+                    retained, Shared::null(), Ordering::AcqRel,
+                    Ordering::Relaxed, guard).is_ok() {
+                unsafe { self.defer_drop_source(retained, guard); }
+            }
+        }
+        let (source_data, retained) = match self.retry {
+            Some(ref retry) =>
+                ((retry.clone_source)(&pending.source), Some(pending)),
+            None => (pending.source, None),
+        };
+        // A function set before the source was published must be seen
+        // here, which the acquiring swap of the source ensures.
+        let next_fn = self.next_transform_fn.swap(Shared::null(),
+                                                  Ordering::AcqRel, guard);
+        let transform_fn = unsafe {
+            if let Some(next_fn) = next_fn.try_into_owned() {
+                *self.transform_fn.get() = *next_fn.into_box();
+            }
+            &mut *self.transform_fn.get()
+        };
+        // GUARD keeps the value from being freed should set_value and the
+        // like replace it, so it outlives the call.
+        let previous = unsafe {
+            self.value.load(Ordering::Acquire, guard).as_ref()
+                .map(|published| &published.value)
+        };
+        let run = || transform_fn.transform_from(source_data, previous);
+        // Only adaptive instances need the time.
+        let started = match self.eager {
+            Some(Eager { adaptive: Some(_), .. }) => Some(self.now()),
+            _ => None,
+        };
+        let outcome = match self.panic_policy {
+            PanicPolicy::Propagate => Ok(run()),
+            PanicPolicy::Catch | PanicPolicy::Poison =>
+                panic::catch_unwind(AssertUnwindSafe(run)),
+        };
+        if let Some(started) = started {
+            self.record_transform_time(
+                self.now().saturating_duration_since(started));
+        }
+        let newval = match outcome {
+            Ok(Ok(newval)) => newval,
+            Ok(Err(error)) => {
+                record_failure(&self.error, self.next_generation(), error,
+                               guard);
+                if let (Some(retry), Some(pending)) =
+                    (self.retry.as_ref(), retained) {
+                    self.requeue(pending, retry, guard);
+                }
+                return;
+            }
+            Err(payload) => {
+                record_failure(&self.panic, self.next_generation(),
+                               panic_message(&*payload), guard);
+                if self.panic_policy == PanicPolicy::Poison {
+                    self.poisoned.store(true, Ordering::Relaxed);
+                    // Make readers and waiters come and see.
+                    self.sources_published.fetch_add(1, Ordering::Release);
+                    self.waiters.notify();
+                    panic::resume_unwind(payload);
+                }
+                if let (Some(retry), Some(pending)) =
+                    (self.retry.as_ref(), retained) {
+                    self.requeue(pending, retry, guard);
+                }
+                return;
+            }
+        };
+        let _ = self.install(newval, true, |_| superseded(), guard);
+        if let Some(pending) = retained {
+            self.drop_source(pending.source);
+        }
     }
 
-    // Transform and drop the newly published SOURCE if available.  Caches the
-    // new value and returns a copy.  Returns None if no new source exists, if
-    // the lock is already taken, or if transformation fails.
-    fn try_transform(&self, scope: &Scope) -> Option<T> {
-        if let Some(_lock_guard) = self.transform_lock.try_lock() {
-            let source = self.source.swap(Ptr::null(), Ordering::AcqRel, &scope);
-            if source.is_null() {
-                return None;
+    fn next_generation(&self) -> u64 {
+        self.generation.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Publish VALUE under the next generation, unless SUPERSEDED says the
+    // current value must stay, in which case VALUE is handed back.  The
+    // generation is drawn after loading the value to replace, so that
+    // generations grow in the order values are installed.  TRANSFORMED
+    // tells whether VALUE came from the transform function.
+    fn install<F>(&self, value: T, transformed: bool, superseded: F,
+                  guard: &Guard) -> Result<u64, T>
+        where F: Fn(Shared<Published<T>>) -> bool
+    {
+        let mut node = Owned::new(Published {
+            generation: 0,
+            installed_at: self.ttl.map(|_| self.now()),
+            transformed,
+            value,
+        });
+        let mut current = self.value.load(Ordering::Acquire, guard);
+        loop {
+            if superseded(current) {
+                return Err(node.into_box().value);
             }
-            let source_data;
-            unsafe {
-                source_data = ::std::ptr::read(source.as_raw());
-                scope.defer_free(source);
+            let generation = self.next_generation();
+            node.generation = generation;
+            match self.value.compare_exchange(current, node, Ordering::AcqRel,
+                                              Ordering::Acquire, guard) {
+                Ok(_) => {
+                    if !current.is_null() {
+                        unsafe { guard.defer_destroy(current); }
+                    }
+                    self.waiters.notify();
+                    return Ok(generation);
+                }
+                Err(err) => {
+                    current = err.current;
+                    node = err.new;
+                }
             }
-            let newval = match (self.transform_fn)(source_data) {
-                Some(newval) => newval,
-                None => return None,
-            };
-            let prev = self.value.swap(Owned::new(newval.clone()).into_ptr(&scope),
-                                       Ordering::AcqRel, &scope);
-            unsafe {
-                if !prev.is_null() {
-                    scope.defer_drop(prev);
+        }
+    }
+
+    // Publish VALUE directly, bypassing the transform function, and return
+    // its generation.  Pending sources are dropped, and so are the result
+    // of a transform in progress and the source retained for
+    // set_transform_fn_and_rerun, as all of them predate VALUE.  Panics if
+    // called from within the transform function, see PanicPolicy.
+    pub fn set_value(&self, value: T) -> u64 {
+        if self.transform_lock.is_held_by_current_thread() {
+            panic!("LazyTransform::set_value called from within its \
+                    transform function");
+        }
+        let guard = &epoch::pin();
+        for pending in self.take_pending(guard) {
+            self.drop_source(pending.source);
+        }
+        self.value_resets.fetch_add(1, Ordering::SeqCst);
+        // The retained source predates VALUE as well, so there is nothing
+        // left to rerun.
+        let last = self.last_source.swap(Shared::null(), Ordering::SeqCst,
+                                         guard);
+        if !last.is_null() {
+            unsafe { self.defer_drop_source(last, guard); }
+        }
+        let generation = match self.install(value, false, |_| false, guard) {
+            Ok(generation) => generation,
+            Err(_) => unreachable!(),
+        };
+        // Let readers know there's news.
+        self.sources_published.fetch_add(1, Ordering::Release);
+        generation
+    }
+
+    // Replace the current value with F applied to it, retrying with the
+    // newer value if it changes under our feet, so F may be called more
+    // than once.  Returns the new generation, or None if there is no value
+    // yet.  A transform in progress isn't affected and may publish its
+    // value afterwards, with an incremental one building on the value it
+    // started with.
+    pub fn update_value<F: FnMut(&T) -> T>(&self, mut f: F) -> Option<u64> {
+        let guard = &epoch::pin();
+        loop {
+            let current = self.value.load(Ordering::Acquire, guard);
+            let updated = f(unsafe { &current.as_ref()?.value });
+            if let Ok(generation) = self.install(updated, false,
+                                                 |now| now != current, guard) {
+                self.sources_published.fetch_add(1, Ordering::Release);
+                return Some(generation);
+            }
+        }
+    }
+
+    // Publish VALUE only if the current value's generation is EXPECTED, 0
+    // meaning no value, and return the new generation.  Otherwise VALUE is
+    // handed back.  Like update_value, this doesn't affect transforms.
+    pub fn compare_and_set_value(&self, expected: u64, value: T)
+                                 -> Result<u64, T> {
+        let guard = &epoch::pin();
+        let generation_of = |published: Shared<Published<T>>| unsafe {
+            published.as_ref().map_or(0, |published| published.generation)
+        };
+        let generation = self.install(
+            value, false, |current| generation_of(current) != expected,
+            guard)?;
+        self.sources_published.fetch_add(1, Ordering::Release);
+        Ok(generation)
+    }
+
+    // Take apart the LazyTransform, returning the source still waiting to be
+    // transformed, if any, and the current value.  In lossless mode only
+    // the latest source is returned, and in merge mode the merged ones.
+    pub fn into_inner(self) -> (Option<S>, Option<T>) {
+        let guard = &epoch::pin();
+        let mut pending = self.take_pending(guard).into_iter();
+        let source = match self.coalesce {
+            Coalesce::Merge(ref merge) => pending.next().map(|first| {
+                pending.fold(first.source, |older, newer| {
+                    merge(older, newer.source)
+                })
+            }),
+            _ => pending.fold(None, |older, newer| {
+                if let Some(older) = older {
+                    self.drop_source(older);
+                }
+                Some(newer.source)
+            }),
+        };
+        // The rest is left to drop.
+        let value = unsafe { take_owned(&self.value) }
+            .map(|published| published.into_box().value);
+        (source, value)
+    }
+
+    // Transform the new source, if any, unless in eager mode, and return the
+    // current value.
+    fn load_published<'g>(&self, guard: &'g Guard) -> Option<&'g Published<T>> {
+        self.check_poisoned();
+        self.count_read();
+        let source = self.source.load(Ordering::Relaxed, guard);
+        if !source.is_null() {
+            self.transform_on_read(guard);
+        }
+        let published = unsafe {
+            self.value.load(Ordering::Acquire, guard).as_ref()
+        };
+        self.apply_ttl(published, guard)
+    }
+
+    // Wait on behalf of one of the blocking methods.  Calling those from
+    // within the transform function would wait for the caller to finish,
+    // so that is detected and reported as a panic, see PanicPolicy.
+    fn block_until<R, F>(&self, deadline: Option<Instant>, poll: F) -> Option<R>
+        where F: FnMut() -> Result<R, Option<Instant>>
+    {
+        if self.transform_lock.is_held_by_current_thread() {
+            panic!("LazyTransform waited on from within its transform function");
+        }
+        self.waiters.wait_until(deadline, poll)
+    }
+
+    fn check_poisoned(&self) {
+        if self.poisoned.load(Ordering::Relaxed) {
+            panic!("LazyTransform poisoned by a panic in the transform function");
+        }
+    }
+
+    // Whether every source published before TAKES read START has been dealt
+    // with, so that the value loaded after this returns true reflects them.
+    fn is_fresh_since(&self, start: u64, guard: &Guard) -> bool {
+        let done = self.takes_done.load(Ordering::Acquire);
+        // A take begun afterwards found them all, and takes are done in
+        // order.
+        if done > start {
+            return true;
+        }
+        // Otherwise they must all have been taken by takes that are done.
+        // Once they're gone, no later take means that's what happened.
+        done == start
+            && self.source.load(Ordering::SeqCst, guard).is_null()
+            && self.takes.load(Ordering::SeqCst) == start
+    }
+
+    // How long the oldest source not yet reflected in the value has been
+    // waiting as of NOW, be it pending or being transformed.
+    fn staleness(&self, now: Instant, guard: &Guard) -> Duration {
+        let pending = unsafe { self.source.load(Ordering::Acquire, guard).as_ref() }
+            .and_then(|pending| pending.published_at);
+        // The lock holder records the time before taking the source, so if
+        // the source was gone, this sees it.
+        let in_flight = match self.staleness_base {
+            Some(base) if self.transform_lock.is_locked() => {
+                match self.in_flight_since.load(Ordering::Acquire) {
+                    NOT_IN_FLIGHT => None,
+                    nanos => Some(base + Duration::from_nanos(nanos)),
                 }
             }
-            return Some(newval);
+            _ => None,
+        };
+        [pending, in_flight].iter().filter_map(|&since| since).min()
+            .map_or(Duration::from_secs(0),
+                    |since| now.saturating_duration_since(since))
+    }
+
+    // Return the counters kept by get_transformed_within.
+    pub fn staleness_stats(&self) -> StalenessStats {
+        let counters = &self.staleness;
+        StalenessStats {
+            within_bound: counters.within_bound.load(Ordering::Relaxed),
+            exceeded: counters.exceeded.load(Ordering::Relaxed),
+            max_staleness: Duration::from_nanos(
+                counters.max_staleness.load(Ordering::Relaxed)),
+            total_staleness: Duration::from_nanos(
+                counters.total_staleness.load(Ordering::Relaxed)),
+        }
+    }
+
+    // Whether every source published so far has been dealt with, so that
+    // the value loaded after this returns true reflects them.  The source
+    // must be checked first: the lock holder takes it out only after
+    // locking, and unlocks only after publishing the value.
+    fn is_settled(&self, guard: &Guard) -> bool {
+        self.source.load(Ordering::Acquire, guard).is_null()
+            && !self.transform_lock.is_locked()
+    }
+
+    // Like get_transformed, but borrow the value instead of cloning it, so
+    // T needn't be Clone.  The returned guard keeps the current thread
+    // pinned, which holds back reclamation of replaced values and sources,
+    // so it should be dropped soon.
+    pub fn load<'a>(&'a self) -> Option<ValueGuard<'a, T>> {
+        let guard = epoch::pin();
+        let value = self.load_published(&guard)
+            .map(|published| &published.value as *const T);
+        value.map(|value| ValueGuard { _guard: guard, value, _lt: PhantomData })
+    }
+
+    // Like load, but pass the value to F and return its result.
+    pub fn with_transformed<R, F: FnOnce(&T) -> R>(&self, f: F) -> Option<R> {
+        let guard = &epoch::pin();
+        self.load_published(guard).map(|published| f(&published.value))
+    }
+
+    // Whether a source has been published but not yet taken for
+    // transformation.  A source waiting to be retried counts as pending.
+    pub fn has_pending_source(&self) -> bool {
+        let guard = &epoch::pin();
+        !self.source.load(Ordering::Acquire, guard).is_null()
+    }
+
+    // Whether some thread is running the transform function right now.
+    pub fn is_transforming(&self) -> bool {
+        self.transform_lock.is_locked()
+    }
+
+    // Whether a transformed value is available.
+    pub fn has_value(&self) -> bool {
+        let guard = &epoch::pin();
+        unsafe { self.value.load(Ordering::Acquire, guard).as_ref() }
+            .is_some_and(|published| !self.is_discarded(published))
+    }
+
+    // Whether reads treat PUBLISHED as gone, having expired under
+    // Expiry::Discard.
+    fn is_discarded(&self, published: &Published<T>) -> bool {
+        matches!(self.ttl, Some((_, Expiry::Discard)))
+            && self.is_expired(published)
+    }
+
+    // Return the above flags along with the generation of the current value.
+    // Unlike the getters, none of these trigger a transform.  The fields are
+    // read one after another, so they might not reflect a single instant if
+    // other threads are busy.
+    pub fn snapshot_state(&self) -> StateSnapshot {
+        let guard = &epoch::pin();
+        let value = unsafe { self.value.load(Ordering::Acquire, guard).as_ref() }
+            .filter(|value| !self.is_discarded(value));
+        StateSnapshot {
+            has_pending_source: self.has_pending_source(),
+            is_transforming: self.is_transforming(),
+            has_value: value.is_some(),
+            generation: value.map_or(0, |value| value.generation),
+        }
+    }
+
+    // Return the last error reported by the transform function, along with
+    // the generation of the failed transform.  The error is kept after
+    // subsequent successful transforms; compare the generation with the one
+    // from get_with_version to tell whether it's still relevant.
+    pub fn last_error(&self) -> Option<(u64, FN::Error)>
+        where FN::Error: Clone
+    {
+        let guard = &epoch::pin();
+        unsafe {
+            self.error.load(Ordering::Acquire, guard).as_ref()
+                .map(|failure| (failure.generation, failure.error.clone()))
         }
-        None
     }
+}
 
+impl<T: Clone, S, FN: Transform<S, T>> LazyTransform<T, S, FN> {
     // Lazily generate a new value if a new source is provided.  Otherwise,
-    // return the cached value.
+    // return the cached value.  In eager mode, always return the cached
+    // value, leaving the new source to the executor.  Likewise when called
+    // from within the transform function, which gets the value it is about
+    // to replace.
     pub fn get_transformed(&self) -> Option<T> {
-        epoch::pin(|scope| {
-            let source = self.source.load(Ordering::Relaxed, &scope);
-            if !source.is_null() {
-                let newval = self.try_transform(&scope);
-                if newval.is_some() {
-                    return newval;
+        self.with_transformed(T::clone)
+    }
+
+    // Like get_transformed, but for instances known to have a value, such
+    // as those created with with_value.  Panics if there is none.
+    pub fn get(&self) -> T {
+        self.get_transformed()
+            .expect("LazyTransform::get called before a value was set")
+    }
+
+    // Like get_transformed, but also return the generation of the value.
+    // Generations of the values observed by any one thread never decrease,
+    // and a different generation means a different value.
+    pub fn get_with_version(&self) -> Option<(u64, T)> {
+        let guard = &epoch::pin();
+        self.load_published(guard)
+            .map(|published| (published.generation, published.value.clone()))
+    }
+
+    // Like get_transformed, but also return whether the value has expired,
+    // see with_ttl.  Only Expiry::KeepStale and Expiry::Rerun return
+    // expired values.
+    pub fn get_with_expiry(&self) -> Option<(T, bool)> {
+        let guard = &epoch::pin();
+        self.load_published(guard).map(|published| {
+            (published.value.clone(), self.is_expired(published))
+        })
+    }
+
+    // Like get_with_version, but return None unless the value's generation
+    // is newer than SINCE, typically the generation of the last value the
+    // caller has seen.  An unchanged value isn't cloned.
+    pub fn get_if_changed(&self, since: u64) -> Option<(u64, T)> {
+        let guard = &epoch::pin();
+        match self.load_published(guard) {
+            Some(published) if published.generation > since =>
+                Some((published.generation, published.value.clone())),
+            _ => None,
+        }
+    }
+
+    // Like get_transformed, but never return a value that predates a source
+    // published before the call.  If such a source is pending, transform it,
+    // and if someone else is at it, wait for them, so reads are
+    // linearizable with respect to set_source.  Sources dropped by
+    // set_value aren't waited for.  In eager mode, this waits for the
+    // executor.
+    pub fn get_transformed_fresh(&self) -> Option<T> {
+        self.count_read();
+        let start = self.takes.load(Ordering::SeqCst);
+        self.get_fresh_since(start)
+    }
+
+    // Like get_transformed, but if the value is staler than MAX_STALENESS,
+    // that is, a source has been waiting to be reflected in it for longer,
+    // act like get_transformed_fresh.  Staleness is measured with the clock
+    // set by with_clock, and recorded for staleness_stats.  Requires
+    // with_staleness_tracking.
+    pub fn get_transformed_within(&self, max_staleness: Duration)
+                                  -> Option<T> {
+        assert!(self.staleness_base.is_some(),
+                "get_transformed_within requires with_staleness_tracking");
+        let start = self.takes.load(Ordering::SeqCst);
+        let staleness = self.staleness(self.now(), &epoch::pin());
+        let within_bound = staleness <= max_staleness;
+        self.staleness.record(staleness, within_bound);
+        if within_bound {
+            return self.get_transformed();
+        }
+        self.count_read();
+        self.get_fresh_since(start)
+    }
+
+    fn get_fresh_since(&self, start: u64) -> Option<T> {
+        let value = self.block_until(None, || {
+            self.check_poisoned();
+            let guard = &epoch::pin();
+            if !self.is_fresh_since(start, guard) {
+                self.transform_on_read(guard);
+                if !self.is_fresh_since(start, guard) {
+                    return Err(self.retry_at());
                 }
             }
-            unsafe {
-                self.value.load(Ordering::Acquire, &scope)
-                    .as_ref().map(T::clone)
+            let published = self.apply_ttl(
+                unsafe { self.value.load(Ordering::Acquire, guard).as_ref() },
+                guard);
+            Ok(published.map(|published| published.value.clone()))
+        });
+        value.unwrap()
+    }
+
+    // Return a handle for repeated reads from a single thread.  It caches the
+    // value and only goes back to the LazyTransform after a new source has
+    // been published.
+    pub fn reader<'a>(&'a self) -> Reader<'a, T, S, FN> {
+        Reader { lt: self, seen: None, cached: None }
+    }
+
+    // Like get_transformed, but report failure of the most recent transform
+    // as Err.  Once a later source transforms successfully, the new value is
+    // returned again.
+    pub fn get_transformed_result(&self) -> Option<Result<T, FN::Error>>
+        where FN::Error: Clone
+    {
+        let guard = &epoch::pin();
+        let published = self.load_published(guard);
+        let failure = unsafe { self.error.load(Ordering::Acquire, guard).as_ref() };
+        match (failure, published) {
+            (Some(failure), Some(published))
+                if failure.generation > published.generation =>
+                Some(Err(failure.error.clone())),
+            (Some(failure), None) => Some(Err(failure.error.clone())),
+            (_, published) => published.map(|published| Ok(published.value.clone())),
+        }
+    }
+
+    // Like get_transformed, but block the calling thread until a value is
+    // available.  This and the other blocking methods panic if called from
+    // within the transform function, see PanicPolicy.
+    pub fn wait_for_value(&self) -> T {
+        self.block_until(None, || self.poll_value()).unwrap()
+    }
+
+    // Like wait_for_value, but give up and return None after TIMEOUT.
+    pub fn wait_for_value_timeout(&self, timeout: Duration) -> Option<T> {
+        self.block_until(Some(Instant::now() + timeout), || self.poll_value())
+    }
+
+    // Block until a value newer than generation SEEN is available and return
+    // it along with its generation.  Pass the generation returned by the
+    // previous call to wait for the next change, or 0 to accept any value.
+    pub fn wait_for_change(&self, seen: u64) -> (u64, T) {
+        self.block_until(None, || self.poll_newer(seen)).unwrap()
+    }
+
+    // Like wait_for_change, but give up and return None after TIMEOUT.
+    pub fn wait_for_change_timeout(&self, seen: u64, timeout: Duration)
+                                   -> Option<(u64, T)> {
+        self.block_until(Some(Instant::now() + timeout),
+                         || self.poll_newer(seen))
+    }
+
+    // Return a future that resolves to the first value newer than generation
+    // SEEN, along with its generation.  This is the async counterpart of
+    // wait_for_change and doesn't depend on any particular runtime.
+    pub fn changed<'a>(&'a self, seen: u64) -> Changed<'a, T, S, FN> {
+        Changed { lt: self, seen, slot: WaitSlot::new() }
+    }
+
+    // Return a stream that yields each newly published value, starting with
+    // the current one, if any.  Values published in quick succession may be
+    // coalesced into the latest one, just like with get_transformed.
+    pub fn updates<'a>(&'a self) -> Updates<'a, T, S, FN> {
+        Updates { lt: self, seen: 0, slot: WaitSlot::new() }
+    }
+
+    // Polling functions for Waiters::wait_until.
+    fn poll_value(&self) -> Result<T, Option<Instant>> {
+        self.get_transformed().ok_or_else(|| self.retry_at())
+    }
+
+    fn poll_newer(&self, seen: u64) -> Result<(u64, T), Option<Instant>> {
+        self.get_if_changed(seen).ok_or_else(|| self.retry_at())
+    }
+}
+
+impl<T, S, FN: Transform<S, T>> Drop for LazyTransform<T, S, FN> {
+    fn drop(&mut self) {
+        // Nodes replaced earlier are already queued for destruction.  The
+        // current ones can't be reachable from other threads any more, as
+        // guards and readers borrow self, so they can go right away.
+        unsafe {
+            let mut source = take_owned(&self.source);
+            while let Some(pending) = source {
+                let pending = *pending.into_box();
+                source = take_owned(&pending.older);
+                self.drop_source(pending.source);
+            }
+            if let Some(last_source) = take_owned(&self.last_source) {
+                self.drop_source(last_source.into_box().source);
             }
-        })
+            drop(take_owned(&self.value));
+            drop(take_owned(&self.error));
+            drop(take_owned(&self.panic));
+            drop(take_owned(&self.next_transform_fn));
+        }
+    }
+}
+
+// Replace the failure stored in SLOT.
+fn record_failure<E>(slot: &Atomic<Failure<E>>, generation: u64, error: E,
+                     guard: &Guard) {
+    let prev = slot.swap(Owned::new(Failure { generation, error }),
+                         Ordering::AcqRel, guard);
+    if !prev.is_null() {
+        unsafe { guard.defer_destroy(prev); }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<Any>".to_owned()
+    }
+}
+
+// Take the node out of ATOMIC without deferring, for use when no other thread
+// can be accessing it.
+unsafe fn take_owned<T>(atomic: &Atomic<T>) -> Option<Owned<T>> {
+    atomic.swap(Shared::null(), Ordering::Relaxed, epoch::unprotected())
+        .try_into_owned()
+}
+
+// Caching read handle returned by LazyTransform::reader.
+pub struct Reader<'a, T: 'a, S: 'a, FN: 'a + Transform<S, T>> {
+    lt: &'a LazyTransform<T, S, FN>,
+    // LT.sources_published as of the last refresh, or None if a source was
+    // still pending or being transformed then.
+    seen: Option<u64>,
+    cached: Option<(u64, Arc<T>)>,
+}
+
+impl<'a, T: Clone, S, FN: Transform<S, T>> Reader<'a, T, S, FN> {
+    // Like LazyTransform::get_transformed, but if no source has been
+    // published since the last call, return the cached value at the cost of
+    // a single atomic load.
+    pub fn get(&mut self) -> Option<&Arc<T>> {
+        let published = self.lt.sources_published.load(Ordering::Relaxed);
+        // Values can expire without anything being published.
+        if self.seen != Some(published) || self.lt.ttl.is_some() {
+            self.refresh();
+        }
+        self.cached.as_ref().map(|cached| &cached.1)
+    }
+
+    // Generation of the value last returned by get.
+    pub fn generation(&self) -> u64 {
+        self.cached.as_ref().map_or(0, |cached| cached.0)
+    }
+
+    #[cold]
+    fn refresh(&mut self) {
+        let lt = self.lt;
+        let published = lt.sources_published.load(Ordering::Acquire);
+        lt.check_poisoned();
+        let guard = &epoch::pin();
+        lt.count_read();
+        if !lt.source.load(Ordering::Relaxed, guard).is_null() {
+            lt.transform_on_read(guard);
+        }
+        self.seen = if lt.is_settled(guard) { Some(published) } else { None };
+        let current = lt.apply_ttl(
+            unsafe { lt.value.load(Ordering::Acquire, guard).as_ref() }, guard);
+        match current {
+            Some(current) if self.generation() != current.generation => {
+                self.cached = Some((current.generation,
+                                    Arc::new(current.value.clone())));
+            }
+            Some(_) => (),
+            None => self.cached = None,
+        }
+    }
+}
+
+// Borrowed value returned by LazyTransform::load.
+pub struct ValueGuard<'a, T: 'a> {
+    // Keeps VALUE from being reclaimed.
+    _guard: Guard,
+    value: *const T,
+    _lt: PhantomData<&'a T>,
+}
+
+impl<'a, T> Deref for ValueGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value }
     }
 }
 
+// Free the memory behind PTR once no thread can be reading it, without
+// dropping the pointee, which the caller has moved out of it.
+unsafe fn defer_free<T>(guard: &Guard, ptr: Shared<T>) {
+    let raw = ptr.as_raw() as *mut ManuallyDrop<T>;
+    guard.defer_unchecked(move || drop(Box::from_raw(raw)));
+}
+
+// Holds the owner's thread token, or 0 when unlocked.
 #[derive(Debug)]
-struct LightLock(AtomicBool);
+struct LightLock(AtomicUsize);
 
 impl LightLock {
-    pub fn new() -> LightLock {
-        LightLock(AtomicBool::new(false))
+    pub const fn new() -> LightLock {
+        LightLock(AtomicUsize::new(0))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.0.load(Ordering::Acquire) != 0
+    }
+
+    pub fn is_held_by_current_thread(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == current_thread_token()
     }
 
     pub fn try_lock<'a>(&'a self) -> Option<LightGuard<'a>> {
-        let was_locked = self.0.swap(true, Ordering::Acquire);
-        if was_locked {
-            None
-        } else {
-            Some(LightGuard { lock: self })
-        }
+        self.0.compare_exchange(0, current_thread_token(),
+                                Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| LightGuard { lock: self })
     }
 }
 
+// Address of a thread-local, which is nonzero and unique among live threads.
+fn current_thread_token() -> usize {
+    thread_local!(static TOKEN: u8 = const { 0 });
+    TOKEN.with(|token| token as *const u8 as usize)
+}
+
 struct LightGuard<'a> {
     lock: &'a LightLock,
 }
 
 impl<'a> Drop for LightGuard<'a> {
     fn drop(&mut self) {
-        self.lock.0.store(false, Ordering::Release);
+        self.lock.0.store(0, Ordering::Release);
+    }
+}
+
+// Parking lot for threads blocked in wait_for_* and for tasks awaiting
+// changed() and updates().  Notifying is a fence and a load of COUNT unless
+// someone is actually waiting, so instances that are never waited on pay next
+// to nothing for it.
+#[derive(Debug)]
+struct Waiters {
+    count: AtomicUsize,
+    // Bumped under LOCK by each notification, so that a waiter can tell
+    // whether it missed one between polling and going to sleep.
+    notifications: AtomicUsize,
+    next_task_id: AtomicUsize,
+    // Wakers of pending tasks, keyed by task id.
+    lock: Mutex<Vec<(usize, Waker)>>,
+    cond: Condvar,
+}
+
+impl Waiters {
+    const fn new() -> Waiters {
+        Waiters {
+            count: AtomicUsize::new(0),
+            notifications: AtomicUsize::new(0),
+            next_task_id: AtomicUsize::new(0),
+            lock: Mutex::new(Vec::new()),
+            cond: Condvar::new(),
+        }
+    }
+
+    // Wake up the waiters, if any.  Must be called after the state change
+    // the waiters are interested in has been published.
+    fn notify(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if self.count.load(Ordering::Relaxed) != 0 {
+            let wakers = {
+                let mut wakers = self.lock.lock().unwrap();
+                self.notifications.fetch_add(1, Ordering::Release);
+                self.cond.notify_all();
+                mem::take(&mut *wakers)
+            };
+            // Wake outside the lock, a waker might poll right away.
+            for (_, waker) in wakers {
+                waker.wake();
+            }
+        }
+    }
+
+    fn register(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        // Pairs with the fence in notify(): either the notifier sees our
+        // registration, or the subsequent poll sees the notifier's change.
+        atomic::fence(Ordering::SeqCst);
+    }
+
+    // Call POLL until it returns Ok, sleeping between calls until notified.
+    // POLL runs without holding LOCK because it may end up transforming and
+    // notifying.  If it returns Err(Some(instant)), it is also called again
+    // at that instant without notification.  Returns None if DEADLINE passes
+    // first.
+    fn wait_until<R, F>(&self, deadline: Option<Instant>, mut poll: F)
+                        -> Option<R>
+        where F: FnMut() -> Result<R, Option<Instant>>
+    {
+        self.register();
+        let _registration = WaitRegistration(&self.count);
+        loop {
+            let seen = self.notifications.load(Ordering::Acquire);
+            let recheck_at = match poll() {
+                Ok(result) => return Some(result),
+                Err(recheck_at) => recheck_at,
+            };
+            let guard = self.lock.lock().unwrap();
+            if self.notifications.load(Ordering::Relaxed) != seen {
+                continue;
+            }
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return None;
+            }
+            // A recheck that is already due means someone else is busy
+            // transforming; give them a moment.
+            let recheck_at = recheck_at
+                .map(|recheck_at| recheck_at.max(now + Duration::from_millis(1)));
+            match [deadline, recheck_at].iter().filter_map(|&t| t).min() {
+                None => drop(self.cond.wait(guard).unwrap()),
+                Some(wake_at) => {
+                    drop(self.cond.wait_timeout(guard, wake_at - now).unwrap());
+                }
+            }
+        }
+    }
+
+    // Async version of wait_until: instead of sleeping, leave the task's
+    // waker behind and return Pending.  SLOT keeps the task registered
+    // across polls, until it is passed to unregister().
+    fn poll_until<R, F>(&self, slot: &mut WaitSlot, cx: &mut Context,
+                        mut poll: F) -> Poll<R>
+        where F: FnMut() -> Option<R>
+    {
+        let task_id = match slot.task_id {
+            Some(task_id) => task_id,
+            None => {
+                let task_id = self.next_task_id.fetch_add(1, Ordering::Relaxed);
+                self.register();
+                slot.task_id = Some(task_id);
+                task_id
+            }
+        };
+        loop {
+            let seen = self.notifications.load(Ordering::Acquire);
+            if let Some(result) = poll() {
+                return Poll::Ready(result);
+            }
+            let mut wakers = self.lock.lock().unwrap();
+            if self.notifications.load(Ordering::Relaxed) != seen {
+                continue;
+            }
+            match wakers.iter_mut().find(|entry| entry.0 == task_id) {
+                Some(entry) => entry.1.clone_from(cx.waker()),
+                None => wakers.push((task_id, cx.waker().clone())),
+            }
+            return Poll::Pending;
+        }
+    }
+
+    fn unregister(&self, slot: &mut WaitSlot) {
+        if let Some(task_id) = slot.task_id.take() {
+            self.lock.lock().unwrap().retain(|entry| entry.0 != task_id);
+            self.count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Marks a take done when the lock holder is, including by panicking.
+struct TakeDone<'a, T: 'a, S: 'a, FN: 'a + Transform<S, T>> {
+    lt: &'a LazyTransform<T, S, FN>,
+    take: u64,
+}
+
+impl<'a, T, S, FN: Transform<S, T>> Drop for TakeDone<'a, T, S, FN> {
+    fn drop(&mut self) {
+        if self.lt.staleness_base.is_some() {
+            self.lt.in_flight_since.store(NOT_IN_FLIGHT, Ordering::Relaxed);
+        }
+        self.lt.takes_done.store(self.take, Ordering::Release);
+        // Fresh readers may be waiting for this.
+        self.lt.waiters.notify();
+    }
+}
+
+struct WaitRegistration<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for WaitRegistration<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Registration of a single future or stream with Waiters.
+#[derive(Debug)]
+struct WaitSlot {
+    task_id: Option<usize>,
+}
+
+impl WaitSlot {
+    fn new() -> WaitSlot {
+        WaitSlot { task_id: None }
+    }
+}
+
+// Future returned by LazyTransform::changed.
+pub struct Changed<'a, T: 'a, S: 'a, FN: 'a + Transform<S, T>> {
+    lt: &'a LazyTransform<T, S, FN>,
+    seen: u64,
+    slot: WaitSlot,
+}
+
+impl<'a, T, S, FN> Future for Changed<'a, T, S, FN>
+    where T: Clone, FN: Transform<S, T>
+{
+    type Output = (u64, T);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<(u64, T)> {
+        let this = self.get_mut();
+        let (lt, seen) = (this.lt, this.seen);
+        let result = lt.waiters.poll_until(&mut this.slot, cx,
+                                           || lt.get_if_changed(seen));
+        if result.is_ready() {
+            lt.waiters.unregister(&mut this.slot);
+        }
+        result
+    }
+}
+
+impl<'a, T, S, FN: Transform<S, T>> Drop for Changed<'a, T, S, FN> {
+    fn drop(&mut self) {
+        self.lt.waiters.unregister(&mut self.slot);
+    }
+}
+
+// Stream returned by LazyTransform::updates.
+pub struct Updates<'a, T: 'a, S: 'a, FN: 'a + Transform<S, T>> {
+    lt: &'a LazyTransform<T, S, FN>,
+    seen: u64,
+    slot: WaitSlot,
+}
+
+impl<'a, T, S, FN> Stream for Updates<'a, T, S, FN>
+    where T: Clone, FN: Transform<S, T>
+{
+    type Item = T;
+
+    // Never ends; the stream stays registered between items so that
+    // consecutive polls don't have to register anew.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        let (lt, seen) = (this.lt, this.seen);
+        match lt.waiters.poll_until(&mut this.slot, cx, || lt.get_if_changed(seen)) {
+            Poll::Ready((generation, value)) => {
+                this.seen = generation;
+                Poll::Ready(Some(value))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T, S, FN: Transform<S, T>> Drop for Updates<'a, T, S, FN> {
+    fn drop(&mut self) {
+        self.lt.waiters.unregister(&mut self.slot);
     }
 }
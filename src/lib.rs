@@ -1,4 +1,5 @@
-extern crate coco;
+extern crate crossbeam_epoch;
+extern crate futures_core;
 
 pub mod lazy_transform;
 
@@ -6,3 +7,5 @@ pub use self::lazy_transform::*;
 
 #[cfg(test)]
 mod tests;
+#[cfg(test)]
+mod drop_tests;
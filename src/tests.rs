@@ -1,9 +1,19 @@
-use lazy_transform::LazyTransform;
+use lazy_transform::{AdaptivePolicy, Clock, DynTransform, Executor, Expiry,
+                     LazyTransform, PanicPolicy, RetryPolicy, RetryStats,
+                     StalenessStats, StateSnapshot, Task, TransformerThread};
 
-use std::sync::Arc;
+use std::future::Future;
+use std::collections::HashMap;
+use std::panic;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Barrier, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
 use std::thread;
 use std::time::{Instant, Duration};
 
+use futures_core::Stream;
+
 fn busy_wait(nsecs: u32) {
     let deadline = Instant::now() + Duration::new(0, nsecs);
     while Instant::now() < deadline {
@@ -20,7 +30,7 @@ impl Behavior for (u64, u64) {
     }
 }
 
-fn transform_to_opaque(s: String) -> Option<Arc<Behavior>> {
+fn transform_to_opaque(s: String) -> Option<Arc<dyn Behavior>> {
     let nums: Vec<_> = s.split_whitespace().collect();
     if nums.len() != 2 {
         return None;
@@ -144,3 +154,1972 @@ fn heavy_arc() {
     let t1 = ::std::time::Instant::now();
     println!("heavy done 2 {:?}", t1-t0);
 }
+
+#[test]
+fn wait_for_value() {
+    let lt = Arc::new(LazyTransform::new(transform_to_concrete));
+    assert_eq!(lt.wait_for_value_timeout(Duration::from_millis(10)), None);
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            thread::sleep(Duration::from_millis(50));
+            lt.set_source("42".to_owned());
+        }
+    });
+    assert_eq!(lt.wait_for_value(), 42);
+    producer.join().unwrap();
+    // A value is already there, so this must not block.
+    assert_eq!(lt.wait_for_value_timeout(Duration::from_secs(0)), Some(42));
+}
+
+#[test]
+fn wait_for_change() {
+    let lt = Arc::new(LazyTransform::new(transform_to_concrete));
+    lt.set_source("1".to_owned());
+    let (gen1, val) = lt.wait_for_change(0);
+    assert_eq!(val, 1);
+    assert_eq!(lt.wait_for_change_timeout(gen1, Duration::from_millis(10)),
+               None);
+    // Failed transforms don't count as a change.
+    lt.set_source("garbage".to_owned());
+    assert_eq!(lt.wait_for_change_timeout(gen1, Duration::from_millis(10)),
+               None);
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            thread::sleep(Duration::from_millis(50));
+            lt.set_source("2".to_owned());
+        }
+    });
+    let (gen2, val) = lt.wait_for_change(gen1);
+    assert!(gen2 > gen1);
+    assert_eq!(val, 2);
+    producer.join().unwrap();
+}
+
+#[test]
+fn wait_for_change_many_waiters() {
+    const ITERS: u64 = 1_000;
+    let lt = Arc::new(LazyTransform::new(transform_to_concrete));
+    let waiters: Vec<_> = (0..8).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut seen = 0;
+            loop {
+                let (generation, val) = lt.wait_for_change(seen);
+                assert!(generation > seen);
+                seen = generation;
+                if val == ITERS - 1 {
+                    break;
+                }
+            }
+        }
+    })).collect();
+    for i in 0..ITERS {
+        lt.set_source(format!("{}", i));
+    }
+    for waiter in waiters {
+        waiter.join().unwrap();
+    }
+}
+
+// Minimal executor: poll on the current thread, park until woken.
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on_with<R, F>(mut poll: F) -> R
+    where F: FnMut(&mut Context) -> Poll<R>
+{
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match poll(&mut cx) {
+            Poll::Ready(result) => return result,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    block_on_with(|cx| future.as_mut().poll(cx))
+}
+
+fn block_on_next<St: Stream + Unpin>(stream: &mut St) -> Option<St::Item> {
+    block_on_with(|cx| Pin::new(&mut *stream).poll_next(cx))
+}
+
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn changed_registers_waker() {
+    let lt = LazyTransform::new(transform_to_concrete);
+    let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+    let waker = Waker::from(Arc::clone(&counter));
+    let mut cx = Context::from_waker(&waker);
+    let mut changed = Box::pin(lt.changed(0));
+    assert_eq!(changed.as_mut().poll(&mut cx), Poll::Pending);
+    assert_eq!(changed.as_mut().poll(&mut cx), Poll::Pending);
+    assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+    lt.set_source("7".to_owned());
+    // Polled twice, but registered and woken only once.
+    assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+    assert_eq!(changed.as_mut().poll(&mut cx), Poll::Ready((1, 7)));
+}
+
+#[test]
+fn changed_threaded() {
+    let lt = Arc::new(LazyTransform::new(transform_to_concrete));
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            thread::sleep(Duration::from_millis(50));
+            lt.set_source("1".to_owned());
+            thread::sleep(Duration::from_millis(50));
+            lt.set_source("2".to_owned());
+        }
+    });
+    let (gen1, val) = block_on(lt.changed(0));
+    assert_eq!(val, 1);
+    let (gen2, val) = block_on(lt.changed(gen1));
+    assert!(gen2 > gen1);
+    assert_eq!(val, 2);
+    producer.join().unwrap();
+}
+
+#[test]
+fn updates_stream() {
+    const ITERS: u64 = 1_000;
+    let lt = Arc::new(LazyTransform::new(transform_to_concrete));
+    let consumers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut updates = lt.updates();
+            let mut last = None;
+            loop {
+                let val = block_on_next(&mut updates).unwrap();
+                if let Some(last) = last {
+                    assert!(val > last);
+                }
+                last = Some(val);
+                if val == ITERS - 1 {
+                    break;
+                }
+            }
+        }
+    })).collect();
+    for i in 0..ITERS {
+        lt.set_source(format!("{}", i));
+    }
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+}
+
+fn parse_config(s: String) -> Result<u64, String> {
+    s.parse().map_err(|_| format!("bad config: {}", s))
+}
+
+#[test]
+fn fallible() {
+    let lt = LazyTransform::new_fallible(parse_config);
+    assert_eq!(lt.get_transformed_result(), None);
+    assert_eq!(lt.last_error(), None);
+    lt.set_source("x".to_owned());
+    assert_eq!(lt.get_transformed_result(), Some(Err("bad config: x".to_owned())));
+    assert_eq!(lt.get_transformed(), None);
+    lt.set_source("1".to_owned());
+    assert_eq!(lt.get_transformed_result(), Some(Ok(1)));
+    lt.set_source("y".to_owned());
+    // The error is reported, but the last good value stays in place.
+    assert_eq!(lt.get_transformed_result(), Some(Err("bad config: y".to_owned())));
+    assert_eq!(lt.get_transformed(), Some(1));
+    let (error_gen, error) = lt.last_error().unwrap();
+    assert_eq!(error, "bad config: y");
+    lt.set_source("2".to_owned());
+    let (value_gen, value) = lt.wait_for_change(0);
+    assert_eq!(value, 2);
+    assert!(value_gen > error_gen);
+    assert_eq!(lt.get_transformed_result(), Some(Ok(2)));
+    // The last error is still available for inspection.
+    assert_eq!(lt.last_error(), Some((error_gen, "bad config: y".to_owned())));
+}
+
+#[test]
+fn option_transform_records_failure() {
+    let lt = LazyTransform::new(transform_to_concrete);
+    lt.set_source("1".to_owned());
+    assert_eq!(lt.get_transformed(), Some(1));
+    lt.set_source("garbage".to_owned());
+    assert_eq!(lt.get_transformed(), Some(1));
+    assert_eq!(lt.get_transformed_result(), Some(Err(())));
+    assert!(lt.last_error().is_some());
+}
+
+// Transform that fails the first FAILURES times it's called.
+fn flaky(failures: usize) -> impl Fn(String) -> Option<u64> {
+    let calls = AtomicUsize::new(0);
+    move |s: String| {
+        if calls.fetch_add(1, Ordering::SeqCst) < failures {
+            return None;
+        }
+        s.parse().ok()
+    }
+}
+
+fn no_backoff(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        initial_backoff: Duration::from_secs(0),
+        max_backoff: Duration::from_secs(0),
+    }
+}
+
+#[test]
+fn retry_transient_failure() {
+    let lt = LazyTransform::new(flaky(2)).with_retry(no_backoff(3));
+    lt.set_source("5".to_owned());
+    assert_eq!(lt.get_transformed(), None);
+    assert_eq!(lt.get_transformed(), None);
+    assert_eq!(lt.get_transformed(), Some(5));
+    assert_eq!(lt.retry_stats(), RetryStats { retries: 2, abandoned: 0 });
+}
+
+#[test]
+fn retry_abandon() {
+    let lt = LazyTransform::new(flaky(2)).with_retry(no_backoff(2));
+    lt.set_source("5".to_owned());
+    assert_eq!(lt.get_transformed(), None);
+    assert_eq!(lt.get_transformed(), None);
+    // Given up, nothing left to retry.
+    assert_eq!(lt.get_transformed(), None);
+    assert_eq!(lt.retry_stats(), RetryStats { retries: 1, abandoned: 1 });
+    lt.set_source("6".to_owned());
+    assert_eq!(lt.get_transformed(), Some(6));
+}
+
+#[test]
+fn retry_superseded() {
+    let lt = LazyTransform::new(flaky(1)).with_retry(no_backoff(3));
+    lt.set_source("5".to_owned());
+    assert_eq!(lt.get_transformed(), None);
+    lt.set_source("6".to_owned());
+    assert_eq!(lt.get_transformed(), Some(6));
+    assert_eq!(lt.get_transformed(), Some(6));
+    assert_eq!(lt.retry_stats(), RetryStats { retries: 0, abandoned: 0 });
+}
+
+#[test]
+fn retry_backoff() {
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_secs(1),
+    };
+    let lt = LazyTransform::new(flaky(1)).with_retry(policy);
+    lt.set_source("5".to_owned());
+    let t0 = Instant::now();
+    assert_eq!(lt.get_transformed(), None);
+    // Not due yet.
+    assert_eq!(lt.get_transformed(), None);
+    assert_eq!(lt.retry_stats().retries, 0);
+    // The waiter wakes up by itself when the retry is due.
+    assert_eq!(lt.wait_for_value(), 5);
+    assert!(t0.elapsed() >= Duration::from_millis(50));
+    assert_eq!(lt.retry_stats().retries, 1);
+}
+
+// Deliberately not Clone.
+#[derive(Debug, PartialEq)]
+struct Table(Vec<u64>);
+
+fn build_table(s: String) -> Option<Table> {
+    let n: u64 = s.parse().ok()?;
+    Some(Table(vec![n; 1000]))
+}
+
+#[test]
+fn load_without_clone() {
+    let lt = LazyTransform::new(build_table);
+    assert!(lt.load().is_none());
+    assert_eq!(lt.with_transformed(|t| t.0.len()), None);
+    lt.set_source("3".to_owned());
+    {
+        let table = lt.load().unwrap();
+        assert_eq!(table.0[0], 3);
+        // The borrowed value survives being replaced.
+        lt.set_source("4".to_owned());
+        assert_eq!(lt.with_transformed(|t| t.0[0]), Some(4));
+        assert_eq!(*table, Table(vec![3; 1000]));
+    }
+    assert_eq!(lt.load().unwrap().0[999], 4);
+}
+
+#[test]
+fn load_threaded() {
+    const ITERS: u64 = 10_000;
+    let lt = Arc::new(LazyTransform::new(build_table));
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for i in 0..ITERS {
+                lt.set_source(format!("{}", i));
+            }
+        }
+    });
+    let consumers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            loop {
+                if let Some(table) = lt.load() {
+                    let first = table.0[0];
+                    // Give the producer time to replace the value while we
+                    // still hold it.
+                    thread::yield_now();
+                    assert!(table.0.iter().all(|&n| n == first));
+                    if first == ITERS - 1 {
+                        break;
+                    }
+                }
+            }
+        }
+    })).collect();
+    producer.join().unwrap();
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+}
+
+#[test]
+fn reader_caches() {
+    let lt = LazyTransform::new(transform_to_concrete);
+    let mut reader = lt.reader();
+    assert!(reader.get().is_none());
+    lt.set_source("1".to_owned());
+    let first = Arc::clone(reader.get().unwrap());
+    assert_eq!(*first, 1);
+    // Nothing changed, so the very same Arc comes back.
+    assert!(Arc::ptr_eq(&first, reader.get().unwrap()));
+    // A failed transform keeps the old value.
+    lt.set_source("garbage".to_owned());
+    assert!(Arc::ptr_eq(&first, reader.get().unwrap()));
+    lt.set_source("2".to_owned());
+    assert_eq!(**reader.get().unwrap(), 2);
+    assert!(reader.generation() > 0);
+}
+
+#[test]
+fn reader_heavy() {
+    const ITERS: u64 = 100_000;
+    let lt = Arc::new(LazyTransform::new(transform_to_concrete));
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for i in 0..ITERS {
+                lt.set_source(format!("{}", i));
+            }
+        }
+    });
+    let consumers: Vec<_> = (0..8).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut reader = lt.reader();
+            let mut last = None;
+            loop {
+                let this = reader.get().map(|v| **v);
+                match (last, this) {
+                    (Some(last), Some(this)) => assert!(this >= last),
+                    (Some(_), None) => panic!("Some followed by None"),
+                    _ => ()
+                }
+                last = this;
+                if this == Some(ITERS - 1) {
+                    break;
+                }
+            }
+        }
+    })).collect();
+    producer.join().unwrap();
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+}
+
+#[test]
+fn versioned() {
+    let lt = LazyTransform::new(transform_to_concrete);
+    assert_eq!(lt.get_with_version(), None);
+    assert_eq!(lt.get_if_changed(0), None);
+    lt.set_source("10".to_owned());
+    let (gen1, val) = lt.get_with_version().unwrap();
+    assert_eq!(val, 10);
+    assert_eq!(lt.get_if_changed(0), Some((gen1, 10)));
+    assert_eq!(lt.get_if_changed(gen1), None);
+    lt.set_source("10".to_owned());
+    // Same value, but from a new source.
+    let (gen2, val) = lt.get_if_changed(gen1).unwrap();
+    assert!(gen2 > gen1);
+    assert_eq!(val, 10);
+    assert_eq!(lt.get_with_version(), Some((gen2, 10)));
+}
+
+#[test]
+fn versioned_heavy() {
+    const ITERS: u64 = 100_000;
+    let lt = Arc::new(LazyTransform::new(transform_to_concrete));
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for i in 0..ITERS {
+                lt.set_source(format!("{}", i));
+            }
+        }
+    });
+    let consumers: Vec<_> = (0..8).map(|i| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut last: Option<(u64, u64)> = None;
+            loop {
+                let this = if i % 2 == 0 {
+                    lt.get_with_version()
+                } else {
+                    lt.get_if_changed(last.map_or(0, |l| l.0)).or(last)
+                };
+                match (last, this) {
+                    (Some((last_gen, last_val)), Some((this_gen, this_val))) => {
+                        assert!(this_gen >= last_gen);
+                        // Generations identify values.
+                        if this_gen == last_gen {
+                            assert_eq!(this_val, last_val);
+                        } else {
+                            assert!(this_val > last_val);
+                        }
+                    }
+                    (Some(_), None) => panic!("Some followed by None"),
+                    _ => ()
+                }
+                last = this;
+                if this.map(|t| t.1) == Some(ITERS - 1) {
+                    break;
+                }
+            }
+        }
+    })).collect();
+    producer.join().unwrap();
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+}
+
+#[test]
+fn introspection() {
+    let lt = Arc::new(LazyTransform::new(transform_to_concrete));
+    assert_eq!(lt.snapshot_state(), StateSnapshot {
+        has_pending_source: false,
+        is_transforming: false,
+        has_value: false,
+        generation: 0,
+    });
+    lt.set_source("1".to_owned());
+    assert!(lt.has_pending_source());
+    // Looking doesn't transform.
+    assert!(lt.has_pending_source());
+    assert!(!lt.has_value());
+    assert_eq!(lt.get_transformed(), Some(1));
+    let state = lt.snapshot_state();
+    assert!(!state.has_pending_source && !state.is_transforming);
+    assert!(state.has_value && state.generation > 0);
+    assert_eq!(lt.get_with_version().unwrap().0, state.generation);
+}
+
+#[test]
+fn introspection_transforming() {
+    // Rendezvous with the transform function once it's running, and again
+    // to let it finish.
+    let barrier = Arc::new(Barrier::new(2));
+    let lt = Arc::new(LazyTransform::new({
+        let barrier = Arc::clone(&barrier);
+        move |s: String| {
+            barrier.wait();
+            barrier.wait();
+            s.parse::<u64>().ok()
+        }
+    }));
+    lt.set_source("1".to_owned());
+    let reader = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || lt.get_transformed()
+    });
+    barrier.wait();
+    assert!(lt.is_transforming());
+    assert!(!lt.has_pending_source());
+    assert!(!lt.has_value());
+    barrier.wait();
+    assert_eq!(reader.join().unwrap(), Some(1));
+    assert!(!lt.is_transforming());
+    assert!(lt.has_value());
+}
+
+fn panicky(s: String) -> Option<u64> {
+    if s == "boom" {
+        panic!("cannot transform {}", s);
+    }
+    s.parse().ok()
+}
+
+#[test]
+fn panic_propagate() {
+    let lt = LazyTransform::new(panicky);
+    lt.set_source("1".to_owned());
+    assert_eq!(lt.get_transformed(), Some(1));
+    lt.set_source("boom".to_owned());
+    assert!(panic::catch_unwind(|| lt.get_transformed()).is_err());
+    // The source is gone and the lock released, so life goes on.
+    assert_eq!(lt.get_transformed(), Some(1));
+    assert!(lt.last_panic().is_none() && !lt.is_poisoned());
+    lt.set_source("2".to_owned());
+    assert_eq!(lt.get_transformed(), Some(2));
+}
+
+#[test]
+fn panic_catch() {
+    let lt = LazyTransform::new(panicky).with_panic_policy(PanicPolicy::Catch);
+    lt.set_source("1".to_owned());
+    assert_eq!(lt.get_transformed(), Some(1));
+    lt.set_source("boom".to_owned());
+    assert_eq!(lt.get_transformed(), Some(1));
+    let (generation, message) = lt.last_panic().unwrap();
+    assert_eq!(message, "cannot transform boom");
+    assert!(generation > lt.get_with_version().unwrap().0);
+    lt.set_source("2".to_owned());
+    assert_eq!(lt.get_transformed(), Some(2));
+    assert!(!lt.is_poisoned());
+}
+
+#[test]
+fn panic_catch_retry() {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let lt = LazyTransform::new({
+        let attempts = Arc::clone(&attempts);
+        move |s: String| {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("transient");
+            }
+            s.parse::<u64>().ok()
+        }
+    }).with_panic_policy(PanicPolicy::Catch).with_retry(no_backoff(3));
+    lt.set_source("7".to_owned());
+    assert_eq!(lt.get_transformed(), None);
+    assert_eq!(lt.get_transformed(), Some(7));
+    assert_eq!(lt.last_panic().unwrap().1, "transient");
+}
+
+#[test]
+fn panic_poison() {
+    let lt = Arc::new(LazyTransform::new(panicky)
+                      .with_panic_policy(PanicPolicy::Poison));
+    lt.set_source("1".to_owned());
+    assert_eq!(lt.get_transformed(), Some(1));
+    let waiter = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || lt.wait_for_change(lt.get_with_version().unwrap().0)
+    });
+    lt.set_source("boom".to_owned());
+    assert!(panic::catch_unwind(|| lt.get_transformed()).is_err());
+    assert!(lt.is_poisoned());
+    assert_eq!(lt.last_panic().unwrap().1, "cannot transform boom");
+    // Everyone else gets to know about it.
+    assert!(waiter.join().is_err());
+    assert!(panic::catch_unwind(|| lt.get_transformed()).is_err());
+    assert!(panic::catch_unwind(|| lt.reader().get().cloned()).is_err());
+}
+
+type StaticLazyTransform =
+    LazyTransform<u64, String, fn(String) -> Option<u64>>;
+
+static REENTRANT: StaticLazyTransform =
+    LazyTransform::new(reentrant_transform);
+
+// Transform that looks at the instance it belongs to.
+fn reentrant_transform(s: String) -> Option<u64> {
+    let lt = &REENTRANT;
+    let prev = lt.get_transformed().unwrap_or(0);
+    let waited = panic::catch_unwind(
+        || lt.wait_for_value_timeout(Duration::from_secs(10)));
+    assert!(waited.is_err());
+    // Setting the value would throw away the one being computed.
+    assert!(panic::catch_unwind(|| lt.set_value(100)).is_err());
+    s.parse::<u64>().ok().map(|n| n + prev)
+}
+
+#[test]
+fn reentrancy() {
+    let lt = &REENTRANT;
+    let start = Instant::now();
+    lt.set_source("1".to_owned());
+    // Reading from within the transform sees the value before it.
+    assert_eq!(lt.get_transformed(), Some(1));
+    lt.set_source("2".to_owned());
+    assert_eq!(lt.get_transformed(), Some(3));
+    // Waiting from within the transform panicked rather than deadlocking.
+    assert!(start.elapsed() < Duration::from_secs(5));
+    // Waiting from outside is fine.
+    assert_eq!(lt.wait_for_value(), 3);
+}
+
+#[test]
+fn reentrant_set_value() {
+    type DynLazyTransform =
+        LazyTransform<u64, u64, DynTransform<u64, u64, ()>>;
+    let lt = Arc::new_cyclic(|this: &Weak<DynLazyTransform>| {
+        let this = Weak::clone(this);
+        LazyTransform::new_dyn(move |n: u64| {
+            let lt = this.upgrade().unwrap();
+            // A pending source doesn't get transformed from in here.
+            lt.set_source(n + 1);
+            assert!(lt.has_pending_source());
+            assert_eq!(lt.get_transformed(), None);
+            if n == 1 {
+                lt.set_value(100);
+            }
+            Some(n)
+        }).with_panic_policy(PanicPolicy::Catch)
+    });
+    lt.set_source(1);
+    // The panic is caught like any other, and the value left alone.
+    assert_eq!(lt.get_transformed(), None);
+    let (_, message) = lt.last_panic().unwrap();
+    assert!(message.contains("set_value called from within"), "{}", message);
+    assert_eq!(lt.get_transformed(), Some(2));
+}
+
+fn parse_radix(radix: u32) -> impl Fn(String) -> Option<u64> {
+    move |s: String| u64::from_str_radix(&s, radix).ok()
+}
+
+#[test]
+fn swap_transform() {
+    let lt = LazyTransform::new_dyn(parse_radix(10)).with_retained_source();
+    lt.set_source("10".to_owned());
+    assert_eq!(lt.get_transformed(), Some(10));
+    // Only new sources are affected.
+    lt.set_transform_fn(Box::new(parse_radix(16)));
+    assert_eq!(lt.get_transformed(), Some(10));
+    lt.set_source("10".to_owned());
+    assert_eq!(lt.get_transformed(), Some(16));
+    // Unless the last source is re-run, also with a different type of
+    // function.
+    assert!(lt.set_transform_fn_and_rerun(Box::new(|s: String| {
+        u64::from_str_radix(&s, 8).ok()
+    })));
+    assert_eq!(lt.get_transformed(), Some(8));
+    // Without retained sources, there's nothing to re-run.
+    let lt = LazyTransform::new_dyn(parse_radix(10));
+    lt.set_source("10".to_owned());
+    assert_eq!(lt.get_transformed(), Some(10));
+    assert!(!lt.set_transform_fn_and_rerun(Box::new(parse_radix(2))));
+    assert_eq!(lt.get_transformed(), Some(10));
+}
+
+#[test]
+fn swap_transform_after_set_value() {
+    let lt = LazyTransform::new_dyn(parse_radix(10)).with_retained_source();
+    lt.set_source("10".to_owned());
+    assert_eq!(lt.get_transformed(), Some(10));
+    // The value set directly supersedes the retained source, which is not
+    // re-run over it.
+    lt.set_value(500);
+    assert!(!lt.set_transform_fn_and_rerun(Box::new(parse_radix(16))));
+    assert_eq!(lt.get_transformed(), Some(500));
+    // Sources set afterwards are retained again.
+    lt.set_source("10".to_owned());
+    assert_eq!(lt.get_transformed(), Some(16));
+    assert!(lt.set_transform_fn_and_rerun(Box::new(parse_radix(8))));
+    assert_eq!(lt.get_transformed(), Some(8));
+}
+
+#[test]
+fn swap_transform_threaded() {
+    // Values record the version of the function that produced them.
+    fn versioned(version: u64)
+                 -> DynTransform<String, (u64, u64), ()> {
+        Box::new(move |s: String| s.parse().ok().map(|n| (version, n)))
+    }
+    const VERSIONS: u64 = 200;
+    let lt = Arc::new(LazyTransform::new_dyn(versioned(0))
+                      .with_retained_source());
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for i in 0..20_000 {
+                lt.set_source(i.to_string());
+            }
+        }
+    });
+    let swapper = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for version in 1..VERSIONS + 1 {
+                if version % 2 == 0 {
+                    lt.set_transform_fn(versioned(version));
+                } else {
+                    lt.set_transform_fn_and_rerun(versioned(version));
+                }
+                thread::yield_now();
+            }
+            lt.set_source("0".to_owned());
+        }
+    });
+    let consumers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut last_version = 0;
+            loop {
+                if let Some((version, _)) = lt.get_transformed() {
+                    assert!(version >= last_version,
+                            "version {} after {}", version, last_version);
+                    last_version = version;
+                }
+                if last_version == VERSIONS {
+                    break;
+                }
+            }
+        }
+    })).collect();
+    producer.join().unwrap();
+    swapper.join().unwrap();
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+}
+
+#[test]
+fn stateful_transform() {
+    // Intern the names, counting how many distinct ones were seen.
+    let lt = LazyTransform::new({
+        let mut interned: HashMap<String, Arc<str>> = HashMap::new();
+        move |s: String| {
+            let name = Arc::clone(interned.entry(s.clone())
+                                  .or_insert_with(|| Arc::from(s.as_str())));
+            Some((name, interned.len()))
+        }
+    });
+    lt.set_source("a".to_owned());
+    let (first, _) = lt.get_transformed().unwrap();
+    lt.set_source("b".to_owned());
+    assert_eq!(lt.get_transformed().unwrap().1, 2);
+    lt.set_source("a".to_owned());
+    let (again, count) = lt.get_transformed().unwrap();
+    assert!(Arc::ptr_eq(&first, &again));
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn stateful_transform_threaded() {
+    // Number the calls; a racing transform would skip or repeat numbers.
+    let lt = Arc::new(LazyTransform::new({
+        let mut calls = 0u64;
+        move |s: String| {
+            calls += 1;
+            s.parse::<u64>().ok().map(|_| calls)
+        }
+    }));
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for i in 0..20_000 {
+                lt.set_source(i.to_string());
+            }
+        }
+    });
+    let consumers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for _ in 0..20_000 {
+                if let Some((generation, calls)) = lt.get_with_version() {
+                    assert_eq!(generation, calls);
+                }
+            }
+        }
+    })).collect();
+    producer.join().unwrap();
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+}
+
+#[test]
+fn incremental() {
+    // Each source adds to the list of numbers seen so far.
+    let lt = LazyTransform::new_incremental(
+        |s: String, prev: Option<&Vec<u64>>| {
+            let mut numbers = prev.cloned().unwrap_or_default();
+            numbers.push(s.parse().ok()?);
+            Some(numbers)
+        });
+    lt.set_source("1".to_owned());
+    assert_eq!(lt.get_transformed(), Some(vec![1]));
+    lt.set_source("2".to_owned());
+    lt.set_source("3".to_owned());
+    assert_eq!(lt.get_transformed(), Some(vec![1, 3]));
+    lt.set_source("x".to_owned());
+    lt.set_source("4".to_owned());
+    assert_eq!(lt.get_transformed(), Some(vec![1, 3, 4]));
+}
+
+// Value that can tell whether it has been dropped, and whether its contents
+// are intact.
+struct Index {
+    numbers: Vec<u64>,
+    sum: u64,
+    alive: Arc<AtomicBool>,
+}
+
+impl Index {
+    fn check(&self) {
+        assert!(self.alive.load(Ordering::SeqCst));
+        assert_eq!(self.numbers.iter().sum::<u64>(), self.sum);
+    }
+}
+
+impl Drop for Index {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn incremental_threaded() {
+    let lt = Arc::new(LazyTransform::new_incremental(
+        |s: String, prev: Option<&Index>| {
+            let n = s.parse::<u64>().ok()?;
+            let mut numbers = match prev {
+                Some(prev) => {
+                    prev.check();
+                    // Give readers time to move on while we hold PREV.
+                    for _ in 0..10 {
+                        thread::yield_now();
+                        busy_wait(1000);
+                    }
+                    prev.check();
+                    prev.numbers.clone()
+                }
+                None => vec![],
+            };
+            numbers.push(n);
+            Some(Index {
+                sum: numbers.iter().sum(),
+                numbers,
+                alive: Arc::new(AtomicBool::new(true)),
+            })
+        }));
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for i in 0..2000 {
+                lt.set_source(i.to_string());
+            }
+        }
+    });
+    let consumers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for _ in 0..2000 {
+                if let Some(index) = lt.load() {
+                    index.check();
+                }
+                lt.with_transformed(Index::check);
+            }
+        }
+    })).collect();
+    producer.join().unwrap();
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+}
+
+#[test]
+fn lossless() {
+    let lt = LazyTransform::new_incremental(
+        |s: String, prev: Option<&Vec<u64>>| {
+            let mut numbers = prev.cloned().unwrap_or_default();
+            numbers.push(s.parse().ok()?);
+            Some(numbers)
+        }).with_lossless(None);
+    lt.set_source("1".to_owned());
+    lt.set_source("2".to_owned());
+    lt.set_source("x".to_owned());
+    lt.set_source("3".to_owned());
+    assert!(lt.has_pending_source());
+    assert_eq!(lt.get_transformed(), Some(vec![1, 2, 3]));
+    assert!(!lt.has_pending_source());
+    // Each source got its own transform.
+    assert_eq!(lt.get_with_version().unwrap().0, 4);
+}
+
+#[test]
+fn lossless_capacity() {
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok())
+        .with_lossless(Some(2));
+    assert!(lt.try_set_source("1".to_owned()).is_ok());
+    assert!(lt.try_set_source("2".to_owned()).is_ok());
+    assert_eq!(lt.try_set_source("3".to_owned()), Err("3".to_owned()));
+    // Makes room by transforming the queue itself.
+    lt.set_source_blocking("3".to_owned());
+    assert_eq!(lt.get_with_version(), Some((3, 3)));
+}
+
+#[test]
+fn lossless_capacity_initial_source() {
+    // The source set up front counts against the capacity.
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok())
+        .with_initial_source("1".to_owned())
+        .with_lossless(Some(2));
+    assert!(lt.try_set_source("2".to_owned()).is_ok());
+    assert_eq!(lt.try_set_source("3".to_owned()), Err("3".to_owned()));
+    assert_eq!(lt.get_with_version(), Some((2, 2)));
+    // Taking it out left the queue empty, not overdrawn.
+    assert!(lt.try_set_source("3".to_owned()).is_ok());
+    assert!(lt.try_set_source("4".to_owned()).is_ok());
+    assert_eq!(lt.try_set_source("5".to_owned()), Err("5".to_owned()));
+    lt.set_source("5".to_owned());
+    assert_eq!(lt.get_with_version(), Some((5, 5)));
+}
+
+// Check that each producer's sources are seen exactly once and in order,
+// returning the number seen so far.
+fn sequence_checker(producers: usize) -> impl FnMut(String) -> Option<u64> {
+    let mut expected = vec![0u64; producers];
+    let mut seen = 0;
+    move |s: String| {
+        let mut parts = s.split(' ').map(|part| part.parse::<u64>().unwrap());
+        let (producer, i) = (parts.next().unwrap() as usize, parts.next().unwrap());
+        assert_eq!(i, expected[producer], "producer {}", producer);
+        expected[producer] += 1;
+        seen += 1;
+        Some(seen)
+    }
+}
+
+fn lossless_heavy_with(capacity: Option<usize>) {
+    const PRODUCERS: u64 = 4;
+    const ITERS: u64 = 100_000;
+    let lt = Arc::new(LazyTransform::new(sequence_checker(PRODUCERS as usize))
+                      .with_lossless(capacity));
+    let producers: Vec<_> = (0..PRODUCERS).map(|p| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for i in 0..ITERS {
+                lt.set_source(format!("{} {}", p, i));
+            }
+        }
+    })).collect();
+    let consumers: Vec<_> = (0..8).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut last = None;
+            loop {
+                let this = lt.get_transformed();
+                match (last, this) {
+                    (Some(last), Some(this)) => assert!(this >= last),
+                    (Some(_), None) => panic!("Some followed by None"),
+                    _ => ()
+                }
+                last = this;
+                if this == Some(PRODUCERS * ITERS) {
+                    break;
+                }
+            }
+        }
+    })).collect();
+    for thread in producers.into_iter().chain(consumers) {
+        thread.join().unwrap();
+    }
+    assert_eq!(lt.get_with_version(), Some((PRODUCERS * ITERS,
+                                            PRODUCERS * ITERS)));
+}
+
+#[test]
+fn lossless_heavy() {
+    lossless_heavy_with(None);
+}
+
+#[test]
+fn lossless_heavy_bounded() {
+    lossless_heavy_with(Some(16));
+}
+
+#[test]
+fn merge_sources() {
+    // Sources are "key=value" settings, merged into one string.
+    let lt = LazyTransform::new(|s: String| Some(s))
+        .with_merge(|older: String, newer: String| older + "," + &newer);
+    lt.set_source("a=1".to_owned());
+    assert_eq!(lt.get_transformed(), Some("a=1".to_owned()));
+    lt.set_source("b=2".to_owned());
+    lt.set_source("c=3".to_owned());
+    assert_eq!(lt.get_with_version(), Some((2, "b=2,c=3".to_owned())));
+}
+
+#[test]
+fn append_sources() {
+    let lt = LazyTransform::new_incremental(
+        |deltas: Vec<u64>, prev: Option<&(u64, usize)>| {
+            let (sum, transforms) = prev.cloned().unwrap_or((0, 0));
+            Some((sum + deltas.iter().sum::<u64>(), transforms + 1))
+        }).with_append();
+    lt.push_source(1);
+    lt.push_source(2);
+    lt.set_source(vec![3, 4]);
+    assert_eq!(lt.get_transformed(), Some((10, 1)));
+    lt.push_source(5);
+    assert_eq!(lt.get_transformed(), Some((15, 2)));
+}
+
+#[test]
+fn append_sources_threaded() {
+    const PRODUCERS: u64 = 4;
+    const ITERS: u64 = 50_000;
+    let lt = Arc::new(LazyTransform::new_incremental(
+        |deltas: Vec<u64>, prev: Option<&(u64, u64)>| {
+            let (sum, count) = prev.cloned().unwrap_or((0, 0));
+            Some((sum + deltas.iter().sum::<u64>(),
+                  count + deltas.len() as u64))
+        }).with_append());
+    let producers: Vec<_> = (0..PRODUCERS).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for i in 0..ITERS {
+                lt.push_source(i);
+            }
+        }
+    })).collect();
+    let consumers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            while lt.get_transformed().map(|(_, count)| count)
+                != Some(PRODUCERS * ITERS) {
+            }
+        }
+    })).collect();
+    for thread in producers.into_iter().chain(consumers) {
+        thread.join().unwrap();
+    }
+    let (generation, (sum, _)) = lt.get_with_version().unwrap();
+    assert_eq!(sum, PRODUCERS * ITERS * (ITERS - 1) / 2);
+    // Nothing was lost, and deltas were batched.
+    assert!(generation <= PRODUCERS * ITERS);
+}
+
+#[test]
+fn versioned_sources() {
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok());
+    assert_eq!(lt.set_source_versioned(2, "2".to_owned()), Ok(()));
+    // Older and duplicate sources are handed back, pending or not.
+    assert_eq!(lt.set_source_versioned(1, "1".to_owned()),
+               Err("1".to_owned()));
+    assert_eq!(lt.get_transformed(), Some(2));
+    assert_eq!(lt.set_source_versioned(2, "2".to_owned()),
+               Err("2".to_owned()));
+    assert_eq!(lt.set_source_versioned(5, "5".to_owned()), Ok(()));
+    assert_eq!(lt.get_transformed(), Some(5));
+    // The whole range of sequence numbers can be used.
+    assert_eq!(lt.set_source_versioned(u64::MAX, "7".to_owned()), Ok(()));
+    assert_eq!(lt.get_transformed(), Some(7));
+    assert_eq!(lt.set_source_versioned(u64::MAX, "8".to_owned()),
+               Err("8".to_owned()));
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok());
+    assert_eq!(lt.set_source_versioned(0, "0".to_owned()), Ok(()));
+    assert_eq!(lt.set_source_versioned(0, "1".to_owned()),
+               Err("1".to_owned()));
+    assert_eq!(lt.get_transformed(), Some(0));
+}
+
+#[test]
+fn versioned_sources_redundant_feeders() {
+    const FEEDERS: usize = 4;
+    const ITERS: u64 = 20_000;
+    let lt = Arc::new(LazyTransform::new(|s: String| s.parse::<u64>().ok()));
+    let feeders: Vec<_> = (0..FEEDERS).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut rejected = 0;
+            for i in 0..ITERS {
+                if lt.set_source_versioned(i, i.to_string()).is_err() {
+                    rejected += 1;
+                }
+            }
+            rejected
+        }
+    })).collect();
+    let consumers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut last = None;
+            while last != Some(ITERS - 1) {
+                let this = lt.get_transformed();
+                match (last, this) {
+                    (Some(last), Some(this)) => assert!(this >= last),
+                    (Some(_), None) => panic!("Some followed by None"),
+                    _ => ()
+                }
+                last = this;
+            }
+        }
+    })).collect();
+    let rejected: u64 = feeders.into_iter()
+        .map(|feeder| feeder.join().unwrap())
+        .sum();
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+    // Each sequence number is accepted once.
+    assert_eq!(rejected, (FEEDERS as u64 - 1) * ITERS);
+}
+
+#[test]
+fn set_value() {
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok());
+    let mut reader = lt.reader();
+    assert_eq!(reader.get(), None);
+    lt.set_source("1".to_owned());
+    // Supersedes the pending source.
+    let generation = lt.set_value(10);
+    assert!(!lt.has_pending_source());
+    assert_eq!(lt.get_with_version(), Some((generation, 10)));
+    assert_eq!(reader.get().map(|value| **value), Some(10));
+    lt.set_source("2".to_owned());
+    assert_eq!(lt.get_transformed(), Some(2));
+}
+
+#[test]
+fn update_value() {
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok());
+    assert_eq!(lt.update_value(|n| n + 1), None);
+    lt.set_source("1".to_owned());
+    let (generation, _) = lt.get_with_version().unwrap();
+    let updated = lt.update_value(|n| n + 1).unwrap();
+    assert!(updated > generation);
+    assert_eq!(lt.get_with_version(), Some((updated, 2)));
+}
+
+#[test]
+fn compare_and_set_value() {
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok());
+    assert_eq!(lt.compare_and_set_value(1, 5), Err(5));
+    let generation = lt.compare_and_set_value(0, 5).unwrap();
+    assert_eq!(lt.compare_and_set_value(0, 6), Err(6));
+    let generation = lt.compare_and_set_value(generation, 6).unwrap();
+    assert_eq!(lt.get_with_version(), Some((generation, 6)));
+}
+
+#[test]
+fn update_value_threaded() {
+    const ITERS: u64 = 10_000;
+    let lt = Arc::new(LazyTransform::new(|s: String| s.parse::<u64>().ok()));
+    lt.set_value(0);
+    let updaters: Vec<_> = (0..4).map(|i| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for _ in 0..ITERS {
+                if i % 2 == 0 {
+                    lt.update_value(|n| n + 1);
+                } else {
+                    loop {
+                        let (generation, n) = lt.get_with_version().unwrap();
+                        if lt.compare_and_set_value(generation, n + 1).is_ok() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    })).collect();
+    let consumers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut last = (0, 0);
+            while last.1 != 4 * ITERS {
+                let this = lt.get_with_version().unwrap();
+                assert!(this.0 >= last.0 && this.1 >= last.1);
+                last = this;
+            }
+        }
+    })).collect();
+    for thread in updaters.into_iter().chain(consumers) {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn set_value_during_transform() {
+    // Rendezvous with the transform function once it's running, and again
+    // to let it finish.
+    let barrier = Arc::new(Barrier::new(2));
+    let lt = Arc::new(LazyTransform::new({
+        let barrier = Arc::clone(&barrier);
+        move |s: String| {
+            barrier.wait();
+            barrier.wait();
+            s.parse::<u64>().ok()
+        }
+    }));
+    lt.set_source("1".to_owned());
+    let reader = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || lt.get_transformed()
+    });
+    barrier.wait();
+    let generation = lt.set_value(10);
+    barrier.wait();
+    // The transform started before set_value, so its result is dropped.
+    assert_eq!(reader.join().unwrap(), Some(10));
+    assert_eq!(lt.get_with_version(), Some((generation, 10)));
+    // Whereas update_value doesn't stop a transform.
+    lt.set_source("2".to_owned());
+    let reader = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || lt.get_transformed()
+    });
+    barrier.wait();
+    lt.update_value(|n| n + 1);
+    barrier.wait();
+    assert_eq!(reader.join().unwrap(), Some(2));
+}
+
+#[test]
+fn set_value_then_source() {
+    const ITERS: u64 = 20_000;
+    let lt = Arc::new(LazyTransform::new(|n: u64| Some(n)));
+    let done = Arc::new(AtomicBool::new(false));
+    // Readers keep transforming, so a source may be taken by one that got
+    // going before the set_value preceding it.
+    let readers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let (lt, done) = (Arc::clone(&lt), Arc::clone(&done));
+        move || {
+            while !done.load(Ordering::Relaxed) {
+                lt.get_transformed();
+            }
+        }
+    })).collect();
+    for i in 0..ITERS {
+        lt.set_value(2 * i);
+        lt.set_source(2 * i + 1);
+        // Published after set_value returned, so it must not be taken for
+        // superseded.
+        assert_eq!(lt.get_transformed_fresh(), Some(2 * i + 1));
+    }
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+}
+
+#[test]
+fn initial_value() {
+    let lt = LazyTransform::with_value(|s: String| s.parse::<u64>().ok(), 0);
+    assert_eq!(lt.get(), 0);
+    lt.set_source("x".to_owned());
+    assert_eq!(lt.get(), 0);
+    lt.set_source("1".to_owned());
+    assert_eq!(lt.get(), 1);
+    let lt = LazyTransform::with_source(|s: String| s.parse::<u64>().ok(),
+                                        "2".to_owned());
+    assert!(lt.has_pending_source() && !lt.has_value());
+    assert_eq!(lt.get(), 2);
+}
+
+#[test]
+fn initial_value_builders() {
+    // Also available to the other constructors.
+    let lt = LazyTransform::new_fallible(parse_config).with_initial_value(0);
+    assert_eq!(lt.get(), 0);
+    lt.set_source("x".to_owned());
+    assert_eq!(lt.get_transformed_result(),
+               Some(Err("bad config: x".to_owned())));
+    assert_eq!(lt.get(), 0);
+    let lt = LazyTransform::new_incremental(|s: u64, prev: Option<&u64>| {
+        Some(s + prev.map_or(0, |&prev| prev))
+    }).with_initial_value(10).with_initial_source(1);
+    assert_eq!(lt.get(), 11);
+    let lt = LazyTransform::new_dyn(parse_radix(10))
+        .with_initial_source("10".to_owned());
+    assert!(lt.has_pending_source());
+    lt.set_transform_fn(Box::new(parse_radix(16)));
+    assert_eq!(lt.get(), 16);
+}
+
+#[test]
+#[should_panic(expected = "before a value was set")]
+fn get_without_value() {
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok());
+    lt.get();
+}
+
+#[test]
+fn into_inner() {
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok());
+    assert_eq!(lt.into_inner(), (None, None));
+    let lt = LazyTransform::with_value(|s: String| s.parse::<u64>().ok(), 1);
+    lt.set_source("2".to_owned());
+    assert_eq!(lt.into_inner(), (Some("2".to_owned()), Some(1)));
+    let lt = LazyTransform::with_source(|s: String| s.parse::<u64>().ok(),
+                                        "3".to_owned());
+    lt.get();
+    assert_eq!(lt.into_inner(), (None, Some(3)));
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok())
+        .with_merge(|older, newer| older + &newer);
+    lt.set_source("4".to_owned());
+    lt.set_source("5".to_owned());
+    assert_eq!(lt.into_inner(), (Some("45".to_owned()), None));
+}
+
+fn parse_setting(s: String) -> Option<u64> {
+    s.parse().ok()
+}
+
+static SETTING: StaticLazyTransform = LazyTransform::new(parse_setting);
+
+#[test]
+fn static_instance() {
+    const ITERS: u64 = 20_000;
+    let producer = thread::spawn(|| {
+        for i in 0..ITERS {
+            SETTING.set_source(i.to_string());
+        }
+    });
+    let consumers: Vec<_> = (0..8).map(|_| thread::spawn(|| {
+        let mut reader = SETTING.reader();
+        let mut last = None;
+        while last != Some(ITERS - 1) {
+            let this = reader.get().map(|value| **value);
+            match (last, this) {
+                (Some(last), Some(this)) => assert!(this >= last),
+                (Some(_), None) => panic!("Some followed by None"),
+                _ => ()
+            }
+            last = this;
+        }
+    })).collect();
+    producer.join().unwrap();
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+    assert_eq!(SETTING.get(), ITERS - 1);
+}
+
+// Executor that keeps tasks until told to run them.
+#[derive(Clone, Default)]
+struct QueueExecutor(Arc<Mutex<Vec<Task>>>);
+
+impl QueueExecutor {
+    fn queued(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    fn run_all(&self) {
+        let tasks: Vec<_> = self.0.lock().unwrap().drain(..).collect();
+        for task in tasks {
+            task();
+        }
+    }
+}
+
+impl Executor for QueueExecutor {
+    fn execute(&self, task: Task) {
+        self.0.lock().unwrap().push(task);
+    }
+}
+
+#[test]
+fn eager_transform() {
+    let executor = QueueExecutor::default();
+    let lt = LazyTransform::new(|s: String| s.parse::<u64>().ok())
+        .into_eager(executor.clone());
+    assert!(lt.is_eager());
+    lt.set_source("1".to_owned());
+    lt.set_source("2".to_owned());
+    // Readers leave the source to the executor, which has a single task
+    // for both.
+    assert_eq!(lt.get_transformed(), None);
+    assert_eq!(lt.reader().get(), None);
+    assert!(lt.has_pending_source());
+    assert_eq!(executor.queued(), 1);
+    executor.run_all();
+    assert_eq!(lt.get_transformed(), Some(2));
+    lt.set_source("3".to_owned());
+    assert_eq!(executor.queued(), 1);
+    executor.run_all();
+    assert_eq!(lt.get_transformed(), Some(3));
+    assert_eq!(executor.queued(), 0);
+}
+
+#[test]
+fn eager_initial_source() {
+    let executor = QueueExecutor::default();
+    let lt = LazyTransform::with_source(|s: String| s.parse::<u64>().ok(),
+                                        "7".to_owned())
+        .into_eager(executor.clone());
+    assert_eq!(executor.queued(), 1);
+    executor.run_all();
+    assert_eq!(lt.get_transformed(), Some(7));
+}
+
+#[test]
+fn eager_transformer_thread() {
+    const ITERS: u64 = 20_000;
+    let elsewhere = Arc::new(AtomicUsize::new(0));
+    let lt = LazyTransform::new({
+        let elsewhere = Arc::clone(&elsewhere);
+        move |s: String| {
+            if thread::current().name() != Some("lazy-transform") {
+                elsewhere.fetch_add(1, Ordering::Relaxed);
+            }
+            s.parse::<u64>().ok()
+        }
+    }).into_eager(TransformerThread::new());
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for i in 0..ITERS {
+                lt.set_source(i.to_string());
+            }
+        }
+    });
+    let consumers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut generation = 0;
+            while lt.get_transformed() != Some(ITERS - 1) {
+                generation = lt.wait_for_change(generation).0;
+            }
+        }
+    })).collect();
+    producer.join().unwrap();
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+    assert_eq!(elsewhere.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn eager_lossless_thread_pool() {
+    // Several tasks may run at once on a pool, yet no source must be left
+    // behind.
+    struct Spawner;
+
+    impl Executor for Spawner {
+        fn execute(&self, task: Task) {
+            thread::spawn(task);
+        }
+    }
+
+    const ITERS: u64 = 2_000;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let lt = LazyTransform::new({
+        let seen = Arc::clone(&seen);
+        move |n: u64| {
+            seen.lock().unwrap().push(n);
+            Some(n)
+        }
+    }).with_lossless(None).into_eager(Spawner);
+    for i in 0..ITERS {
+        lt.set_source(i);
+    }
+    let mut generation = 0;
+    while lt.get_transformed() != Some(ITERS - 1) {
+        generation = lt.wait_for_change(generation).0;
+    }
+    assert_eq!(*seen.lock().unwrap(), (0..ITERS).collect::<Vec<_>>());
+}
+
+// Clock that only moves when told to.
+struct ManualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    fn new() -> Arc<ManualClock> {
+        Arc::new(ManualClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        })
+    }
+
+    fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+}
+
+#[test]
+fn adaptive_switching() {
+    let clock = ManualClock::new();
+    let executor = QueueExecutor::default();
+    // How long each transform takes, in microseconds.
+    let cost = Arc::new(AtomicU64::new(5000));
+    let lt = LazyTransform::new({
+        let (clock, cost) = (Arc::clone(&clock), Arc::clone(&cost));
+        move |n: u64| {
+            clock.advance(Duration::from_micros(cost.load(Ordering::Relaxed)));
+            Some(n)
+        }
+    }).with_clock(Arc::clone(&clock)).into_adaptive(executor.clone(),
+                                                    AdaptivePolicy {
+        window: Duration::from_secs(1),
+        eager_above: Duration::from_millis(1),
+        eager_reads_per_sec: 100.,
+        lazy_below: Duration::from_micros(100),
+        lazy_reads_per_sec: 10.,
+    });
+    let mut next = 0;
+    // Publish a source every 100ms, read it READS times and let the
+    // executor run.
+    let mut rounds = |count: usize, reads: usize| {
+        for _ in 0..count {
+            lt.set_source(next);
+            for _ in 0..reads {
+                lt.get_transformed();
+            }
+            executor.run_all();
+            assert_eq!(lt.get_transformed(), Some(next));
+            next += 1;
+            clock.advance(Duration::from_millis(100));
+        }
+        lt.adaptive_stats().unwrap()
+    };
+    // Expensive transforms, but too few reads to bother.
+    let stats = rounds(25, 4);
+    assert!(!stats.eager);
+    assert_eq!(stats.switches, 0);
+    assert_eq!(stats.transform_time, Duration::from_millis(5));
+    assert!(stats.reads_per_sec > 20. && stats.reads_per_sec < 100.);
+    // Readers keep running into them.
+    let stats = rounds(25, 50);
+    assert!(stats.eager && lt.is_eager());
+    assert_eq!(stats.switches, 1);
+    assert!(stats.reads_per_sec > 100.);
+    // Readers no longer transform.
+    let current = lt.get_transformed();
+    lt.set_source(1000);
+    assert_eq!(lt.get_transformed(), current);
+    assert_eq!(executor.queued(), 1);
+    executor.run_all();
+    assert_eq!(lt.get_transformed(), Some(1000));
+    // Between the thresholds, nothing changes.
+    cost.store(500, Ordering::Relaxed);
+    let stats = rounds(25, 50);
+    assert!(stats.eager);
+    assert_eq!(stats.switches, 1);
+    assert_eq!(stats.transform_time, Duration::from_micros(500));
+    // Cheap transforms are back to lazy.
+    cost.store(50, Ordering::Relaxed);
+    let stats = rounds(25, 50);
+    assert!(!stats.eager && !lt.is_eager());
+    assert_eq!(stats.switches, 2);
+    lt.set_source(2000);
+    assert_eq!(lt.get_transformed(), Some(2000));
+    assert_eq!(executor.queued(), 0);
+}
+
+#[test]
+fn adaptive_flapping() {
+    // A policy under which every window switches modes, to check that no
+    // source is left behind in the switch.
+    const ITERS: u64 = 20_000;
+    let lt = LazyTransform::new(|n: u64| {
+        busy_wait(100_000);
+        Some(n)
+    }).into_adaptive(TransformerThread::new(), AdaptivePolicy {
+        window: Duration::from_millis(1),
+        eager_above: Duration::from_secs(0),
+        eager_reads_per_sec: 0.,
+        lazy_below: Duration::from_secs(1),
+        lazy_reads_per_sec: 0.,
+    });
+    let producer = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            for i in 0..ITERS {
+                lt.set_source(i);
+            }
+        }
+    });
+    let consumers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || {
+            let mut generation = 0;
+            while lt.get_transformed() != Some(ITERS - 1) {
+                generation = lt.wait_for_change(generation).0;
+            }
+        }
+    })).collect();
+    producer.join().unwrap();
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+    assert!(lt.adaptive_stats().unwrap().switches > 1);
+}
+
+#[test]
+fn fresh_waits_for_transform() {
+    let (started_tx, started_rx) = mpsc::channel();
+    let (finish_tx, finish_rx) = mpsc::channel::<()>();
+    let lt = Arc::new(LazyTransform::new(move |n: u64| {
+        if n == 1 {
+            started_tx.send(()).unwrap();
+            finish_rx.recv().unwrap();
+        }
+        Some(n)
+    }));
+    lt.set_source(1);
+    let transforming = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || lt.get_transformed()
+    });
+    started_rx.recv().unwrap();
+    lt.set_source(2);
+    // The plain read gives up on the lock and returns what there is.
+    assert_eq!(lt.get_transformed(), None);
+    let done = Arc::new(AtomicBool::new(false));
+    let fresh = thread::spawn({
+        let (lt, done) = (Arc::clone(&lt), Arc::clone(&done));
+        move || {
+            let value = lt.get_transformed_fresh();
+            done.store(true, Ordering::SeqCst);
+            value
+        }
+    });
+    thread::sleep(Duration::from_millis(50));
+    assert!(!done.load(Ordering::SeqCst));
+    finish_tx.send(()).unwrap();
+    assert_eq!(fresh.join().unwrap(), Some(2));
+    assert_eq!(transforming.join().unwrap(), Some(1));
+}
+
+#[test]
+fn fresh_eager() {
+    let executor = QueueExecutor::default();
+    let lt = LazyTransform::new(|n: u64| Some(n))
+        .into_eager(executor.clone());
+    assert_eq!(lt.get_transformed_fresh(), None);
+    lt.set_source(1);
+    let fresh = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || lt.get_transformed_fresh()
+    });
+    while executor.queued() == 0 {
+        thread::yield_now();
+    }
+    executor.run_all();
+    assert_eq!(fresh.join().unwrap(), Some(1));
+}
+
+// Operation on the LazyTransform, with logical timestamps taken before it
+// was invoked and after it returned.
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    invoked: u64,
+    returned: u64,
+    value: Option<u64>,
+}
+
+#[test]
+fn fresh_linearizable() {
+    const WRITES: u64 = 2_000;
+    let clock = Arc::new(AtomicU64::new(0));
+    let tick = {
+        let clock = Arc::clone(&clock);
+        move || clock.fetch_add(1, Ordering::SeqCst)
+    };
+    // Sleeping lets other threads in while the lock is held, even on a
+    // single CPU.
+    let lt = Arc::new(LazyTransform::new(|n: u64| {
+        thread::sleep(Duration::from_micros(50));
+        Some(n)
+    }));
+    let writer = thread::spawn({
+        let (lt, tick) = (Arc::clone(&lt), tick.clone());
+        move || (0..WRITES).map(|n| {
+            let invoked = tick();
+            lt.set_source(n);
+            let returned = tick();
+            busy_wait(20_000);
+            Op { invoked, returned, value: Some(n) }
+        }).collect::<Vec<_>>()
+    });
+    let readers: Vec<_> = (0..4).map(|_| thread::spawn({
+        let (lt, tick) = (Arc::clone(&lt), tick.clone());
+        move || {
+            let mut history = vec![];
+            loop {
+                let invoked = tick();
+                let value = lt.get_transformed_fresh();
+                history.push(Op { invoked, returned: tick(), value });
+                if value == Some(WRITES - 1) {
+                    return history;
+                }
+            }
+        }
+    })).collect();
+    let writes = writer.join().unwrap();
+    let mut reads: Vec<Op> = readers.into_iter()
+        .flat_map(|reader| reader.join().unwrap()).collect();
+    reads.sort_by_key(|read| read.invoked);
+    // With a single writer of increasing values, a read must return at
+    // least the last value written before it was invoked, nothing written
+    // after it returned, and nothing older than what a read that returned
+    // before it was invoked did.
+    let mut by_return = reads.clone();
+    by_return.sort_by_key(|read| read.returned);
+    let (mut preceding, mut returned) = (None, by_return.iter().peekable());
+    for read in &reads {
+        let completed = writes.partition_point(|w| w.returned < read.invoked);
+        let started = writes.partition_point(|w| w.invoked < read.returned);
+        assert!(read.value >= completed.checked_sub(1).map(|n| n as u64),
+                "stale read {:?}", read);
+        assert!(read.value <= started.checked_sub(1).map(|n| n as u64),
+                "read from the future {:?}", read);
+        while let Some(other) =
+            returned.next_if(|other| other.returned < read.invoked) {
+            preceding = preceding.max(other.value);
+        }
+        assert!(read.value >= preceding, "read went back {:?}", read);
+    }
+}
+
+#[test]
+fn bounded_staleness() {
+    let clock = ManualClock::new();
+    let executor = QueueExecutor::default();
+    let lt = LazyTransform::new(|n: u64| Some(n))
+        .with_clock(Arc::clone(&clock))
+        .with_staleness_tracking()
+        .into_eager(executor.clone());
+    let bound = Duration::from_millis(100);
+    lt.set_source(1);
+    clock.advance(Duration::from_millis(60));
+    // Young enough to settle for no value at all.
+    assert_eq!(lt.get_transformed_within(bound), None);
+    // Replaced sources count from when the first one came in.
+    lt.set_source(2);
+    clock.advance(Duration::from_millis(60));
+    let within = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || lt.get_transformed_within(bound)
+    });
+    while lt.staleness_stats().exceeded == 0 {
+        thread::yield_now();
+    }
+    executor.run_all();
+    assert_eq!(within.join().unwrap(), Some(2));
+    // Nothing pending, so the value is as fresh as it gets.
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(lt.get_transformed_within(Duration::from_secs(0)), Some(2));
+    assert_eq!(lt.staleness_stats(), StalenessStats {
+        within_bound: 2,
+        exceeded: 1,
+        max_staleness: Duration::from_millis(120),
+        total_staleness: Duration::from_millis(180),
+    });
+}
+
+#[test]
+fn bounded_staleness_in_flight() {
+    let clock = ManualClock::new();
+    let (started_tx, started_rx) = mpsc::channel();
+    let (finish_tx, finish_rx) = mpsc::channel::<()>();
+    let lt = Arc::new(LazyTransform::new(move |n: u64| {
+        if n == 2 {
+            started_tx.send(()).unwrap();
+            finish_rx.recv().unwrap();
+        }
+        Some(n)
+    }).with_clock(Arc::clone(&clock)).with_staleness_tracking());
+    lt.set_source(1);
+    assert_eq!(lt.get_transformed(), Some(1));
+    lt.set_source(2);
+    let transforming = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || lt.get_transformed()
+    });
+    started_rx.recv().unwrap();
+    // The source is out, but being transformed still counts as waiting.
+    clock.advance(Duration::from_millis(50));
+    assert_eq!(lt.get_transformed_within(Duration::from_millis(100)),
+               Some(1));
+    clock.advance(Duration::from_millis(100));
+    let within = thread::spawn({
+        let lt = Arc::clone(&lt);
+        move || lt.get_transformed_within(Duration::from_millis(100))
+    });
+    while lt.staleness_stats().exceeded == 0 {
+        thread::yield_now();
+    }
+    finish_tx.send(()).unwrap();
+    assert_eq!(within.join().unwrap(), Some(2));
+    assert_eq!(transforming.join().unwrap(), Some(2));
+    assert_eq!(lt.staleness_stats().max_staleness, Duration::from_millis(150));
+}
+
+#[test]
+#[should_panic(expected = "requires with_staleness_tracking")]
+fn bounded_staleness_untracked() {
+    let lt = LazyTransform::with_source(|n: u64| Some(n), 1);
+    lt.get_transformed_within(Duration::from_secs(1));
+}
+
+#[test]
+fn ttl_discard() {
+    let clock = ManualClock::new();
+    let lt = LazyTransform::new(|n: u64| Some(n))
+        .with_clock(Arc::clone(&clock))
+        .with_ttl(Duration::from_secs(10), Expiry::Discard);
+    let mut reader = lt.reader();
+    lt.set_source(1);
+    // The TTL runs from the transform, not from set_source.
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(lt.get_transformed(), Some(1));
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(reader.get().map(|value| **value), Some(1));
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(lt.get_transformed(), None);
+    assert_eq!(lt.get_with_expiry(), None);
+    assert_eq!(reader.get(), None);
+    // The state agrees with the getters.
+    assert!(!lt.has_value());
+    assert!(!lt.snapshot_state().has_value);
+    assert_eq!(lt.snapshot_state().generation, 0);
+    // New values start over.
+    lt.set_source(2);
+    assert_eq!(lt.get_transformed(), Some(2));
+    lt.set_value(3);
+    clock.advance(Duration::from_secs(9));
+    assert_eq!(reader.get().map(|value| **value), Some(3));
+}
+
+#[test]
+fn ttl_keep_stale() {
+    let clock = ManualClock::new();
+    let lt = LazyTransform::with_source(|n: u64| Some(n), 1)
+        .with_clock(Arc::clone(&clock))
+        .with_ttl(Duration::from_secs(10), Expiry::KeepStale);
+    assert_eq!(lt.get_with_expiry(), Some((1, false)));
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(lt.get_transformed(), Some(1));
+    assert_eq!(lt.get_with_expiry(), Some((1, true)));
+    lt.set_source(2);
+    assert_eq!(lt.get_with_expiry(), Some((2, false)));
+    // An initial value expires too, counting from when the TTL was set.
+    let lt = LazyTransform::with_value(|n: u64| Some(n), 1)
+        .with_clock(Arc::clone(&clock))
+        .with_ttl(Duration::from_secs(10), Expiry::KeepStale);
+    clock.advance(Duration::from_secs(9));
+    assert_eq!(lt.get_with_expiry(), Some((1, false)));
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(lt.get_with_expiry(), Some((1, true)));
+}
+
+#[test]
+fn ttl_rerun() {
+    let clock = ManualClock::new();
+    let calls = Arc::new(AtomicUsize::new(0));
+    // Resolves a name to a new address each time, and fails on the third.
+    let lt = LazyTransform::new({
+        let calls = Arc::clone(&calls);
+        move |name: String| {
+            let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            if call == 3 { None } else { Some(format!("{}#{}", name, call)) }
+        }
+    }).with_clock(Arc::clone(&clock)).with_retained_source()
+        .with_ttl(Duration::from_secs(10), Expiry::Rerun);
+    lt.set_source("db".to_owned());
+    assert_eq!(lt.get_with_expiry(), Some(("db#1".to_owned(), false)));
+    clock.advance(Duration::from_secs(10));
+    // The first read after expiry reruns the transform.
+    assert_eq!(lt.get_with_expiry(), Some(("db#2".to_owned(), false)));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    clock.advance(Duration::from_secs(10));
+    // The rerun fails, which leaves the expired value, without more reruns.
+    for _ in 0..3 {
+        assert_eq!(lt.get_with_expiry(), Some(("db#2".to_owned(), true)));
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    lt.set_source("cache".to_owned());
+    assert_eq!(lt.get_transformed(), Some("cache#4".to_owned()));
+}
+
+#[test]
+fn ttl_rerun_set_value() {
+    let clock = ManualClock::new();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let lt = LazyTransform::new({
+        let calls = Arc::clone(&calls);
+        move |n: u64| Some(n * 10 + calls.fetch_add(1, Ordering::SeqCst) as u64)
+    }).with_clock(Arc::clone(&clock)).with_retained_source()
+        .with_ttl(Duration::from_secs(10), Expiry::Rerun);
+    lt.set_source(1);
+    assert_eq!(lt.get_transformed(), Some(10));
+    // Values that didn't come from the source are kept stale instead of
+    // being replaced by a rerun.
+    lt.update_value(|&n| n + 5);
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(lt.get_with_expiry(), Some((15, true)));
+    lt.set_value(99);
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(lt.get_with_expiry(), Some((99, true)));
+    assert_eq!(lt.compare_and_set_value(0, 7), Err(7));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    // Transformed values are rerun again.
+    lt.set_source(2);
+    assert_eq!(lt.get_with_expiry(), Some((21, false)));
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(lt.get_with_expiry(), Some((22, false)));
+}
+
+#[test]
+fn ttl_rerun_eager() {
+    let clock = ManualClock::new();
+    let executor = QueueExecutor::default();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let lt = LazyTransform::new({
+        let calls = Arc::clone(&calls);
+        move |n: u64| Some(n * 10 + calls.fetch_add(1, Ordering::SeqCst) as u64)
+    }).with_clock(Arc::clone(&clock)).with_retained_source()
+        .with_ttl(Duration::from_secs(10), Expiry::Rerun)
+        .into_eager(executor.clone());
+    lt.set_source(1);
+    executor.run_all();
+    assert_eq!(lt.get_transformed(), Some(10));
+    clock.advance(Duration::from_secs(10));
+    // Readers keep getting the expired value until the executor is done.
+    assert_eq!(lt.get_with_expiry(), Some((10, true)));
+    assert_eq!(lt.get_with_expiry(), Some((10, true)));
+    assert_eq!(executor.queued(), 1);
+    executor.run_all();
+    assert_eq!(lt.get_with_expiry(), Some((11, false)));
+}
+
+#[test]
+#[should_panic(expected = "Expiry::Rerun requires with_retained_source")]
+fn ttl_rerun_without_source() {
+    LazyTransform::new(|n: u64| Some(n))
+        .with_ttl(Duration::from_secs(1), Expiry::Rerun);
+}